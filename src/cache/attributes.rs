@@ -0,0 +1,292 @@
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// The metadata of a file or directory that is restored along with it.
+///
+/// Every field is optional, since databases written by older versions lack some
+/// of them; missing attributes are left as they are on restore.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub permissions: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// The access time, in nanoseconds since the epoch.
+    pub atime: Option<i64>,
+    /// The modification time, in nanoseconds since the epoch.
+    pub mtime: Option<i64>,
+    /// The extended attributes, which include POSIX ACLs and security labels.
+    pub xattrs: Option<Xattrs>,
+}
+
+impl Attributes {
+    /// Captures every attribute of the file at `path`.
+    pub fn read(path: &Path, metadata: &Metadata) -> io::Result<Self> {
+        Ok(Attributes {
+            xattrs: Some(Xattrs::read(path)?),
+            ..Attributes::of(metadata)
+        })
+    }
+
+    /// Captures the attributes of a file that are part of its metadata.
+    ///
+    /// Extended attributes are not included.
+    pub fn of(metadata: &Metadata) -> Self {
+        Attributes {
+            permissions: Some(metadata.permissions().mode()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            atime: Some(metadata.atime() * NANOS_PER_SEC + metadata.atime_nsec()),
+            mtime: Some(metadata.mtime() * NANOS_PER_SEC + metadata.mtime_nsec()),
+            xattrs: None,
+        }
+    }
+
+    /// Applies the attributes to the file at `path`.
+    ///
+    /// A symlink itself is changed rather than its target.
+    ///
+    /// The owner goes first, since changing it clears setuid bits and file
+    /// capabilities. The timestamps go last, since the other changes leave
+    /// them alone but anything done afterwards might not.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        // Changing ownership needs privileges, so only try when it differs.
+        let uid = self.uid.filter(|&uid| uid != metadata.uid());
+        let gid = self.gid.filter(|&gid| gid != metadata.gid());
+        if uid.is_some() || gid.is_some() {
+            unix_fs::lchown(path, uid, gid)?;
+        }
+
+        if let Some(xattrs) = &self.xattrs {
+            xattrs.apply(path)?;
+        }
+        // Symlinks have no mode of their own; setting one would follow the link.
+        // Only the owner may change a mode, so leave it alone if it is unchanged.
+        let permissions = self
+            .permissions
+            .filter(|&permissions| permissions != metadata.permissions().mode());
+        if let Some(permissions) = permissions.filter(|_| !metadata.is_symlink()) {
+            fs::set_permissions(path, fs::Permissions::from_mode(permissions))?;
+        }
+
+        self.apply_times(path)
+    }
+
+    /// Applies only the timestamps to the file at `path`, or to the symlink itself.
+    pub fn apply_times(&self, path: &Path) -> io::Result<()> {
+        let (Some(atime), Some(mtime)) = (self.atime, self.mtime) else {
+            return Ok(());
+        };
+
+        utimensat(
+            None,
+            path,
+            &timespec(atime),
+            &timespec(mtime),
+            UtimensatFlags::NoFollowSymlink,
+        )
+        .map_err(io::Error::from)
+    }
+}
+
+/// Converts nanoseconds since the epoch to a `TimeSpec`.
+fn timespec(nanos: i64) -> TimeSpec {
+    TimeSpec::new(
+        nanos.div_euclid(NANOS_PER_SEC),
+        nanos.rem_euclid(NANOS_PER_SEC),
+    )
+}
+
+/// The extended attributes of a file, as name/value pairs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Xattrs(pub Vec<(OsString, Vec<u8>)>);
+
+impl Xattrs {
+    /// Reads every extended attribute of the file at `path` that the caller can see.
+    ///
+    /// Filesystems without extended attribute support yield none.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let path = c_path(path)?;
+        let names = match xattr_call(|buf, size| {
+            // SAFETY: `buf` points to `size` writable bytes.
+            unsafe { libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size) }
+        }) {
+            Ok(names) => names,
+            Err(Errno::ENOTSUP) => return Ok(Xattrs::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut xattrs = Vec::new();
+        for name in names
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+        {
+            let c_name = CString::new(name)?;
+            let value = match xattr_call(|buf, size| {
+                // SAFETY: `buf` points to `size` writable bytes.
+                unsafe { libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buf, size) }
+            }) {
+                Ok(value) => value,
+                // Removed since it was listed.
+                Err(Errno::ENODATA) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            xattrs.push((OsString::from_vec(name.to_vec()), value));
+        }
+        Ok(Xattrs(xattrs))
+    }
+
+    /// Makes the extended attributes of the file at `path` match these.
+    ///
+    /// Attributes that were added since are removed again.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let current = Xattrs::read(path)?;
+        let c_path = c_path(path)?;
+
+        for (name, _) in &current.0 {
+            if self.0.iter().any(|(saved, _)| saved == name) {
+                continue;
+            }
+            let name = CString::new(name.as_bytes())?;
+            // SAFETY: both pointers are valid null-terminated strings.
+            let res = unsafe { libc::lremovexattr(c_path.as_ptr(), name.as_ptr()) };
+            Errno::result(res)?;
+        }
+
+        for (name, value) in &self.0 {
+            if current.0.iter().any(|(n, v)| n == name && v == value) {
+                continue;
+            }
+            let name = CString::new(name.as_bytes())?;
+            // SAFETY: the strings are null-terminated and `value` is valid for its length.
+            let res = unsafe {
+                libc::lsetxattr(
+                    c_path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            Errno::result(res)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stored as a sequence of null-terminated names, each followed by the value's
+/// length as a little-endian `u32` and the value itself.
+impl ToSql for Xattrs {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let mut bytes = Vec::new();
+        for (name, value) in &self.0 {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value);
+        }
+        Ok(ToSqlOutput::from(bytes))
+    }
+}
+
+impl FromSql for Xattrs {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let mut bytes = value.as_blob()?;
+        let mut xattrs = Vec::new();
+        while !bytes.is_empty() {
+            let nul = bytes
+                .iter()
+                .position(|&byte| byte == 0)
+                .ok_or(FromSqlError::InvalidType)?;
+            let name = OsStr::from_bytes(&bytes[..nul]).to_os_string();
+            bytes = &bytes[nul + 1..];
+
+            let (len, rest) = bytes
+                .split_first_chunk::<4>()
+                .ok_or(FromSqlError::InvalidType)?;
+            let len = u32::from_le_bytes(*len) as usize;
+            let value = rest.get(..len).ok_or(FromSqlError::InvalidType)?;
+            xattrs.push((name, value.to_vec()));
+            bytes = &rest[len..];
+        }
+        Ok(Xattrs(xattrs))
+    }
+}
+
+/// Runs a `*xattr` call that fills a buffer, growing it until the result fits.
+fn xattr_call(
+    mut call: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t,
+) -> Result<Vec<u8>, Errno> {
+    loop {
+        let size = Errno::result(call(std::ptr::null_mut(), 0))? as usize;
+        let mut buf = vec![0u8; size];
+        match Errno::result(call(buf.as_mut_ptr() as *mut libc::c_void, size)) {
+            Ok(len) => {
+                buf.truncate(len as usize);
+                return Ok(buf);
+            }
+            // Grew between the two calls.
+            Err(Errno::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Converts a path to a C string for the `*xattr` calls.
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn round_trip(xattrs: &Xattrs) -> Xattrs {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.query_row("SELECT ?", [xattrs], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn xattrs_survive_the_database() {
+        for xattrs in [
+            Xattrs::default(),
+            Xattrs(vec![("user.empty".into(), Vec::new())]),
+            Xattrs(vec![
+                (
+                    "security.selinux".into(),
+                    b"system_u:object_r:tmp_t:s0\0".to_vec(),
+                ),
+                ("user.binary".into(), vec![0, 1, 255, 0]),
+                ("system.posix_acl_access".into(), vec![2; 300]),
+            ]),
+        ] {
+            assert_eq!(round_trip(&xattrs), xattrs);
+        }
+    }
+
+    #[test]
+    fn truncated_xattrs_are_rejected() {
+        let xattrs = Xattrs(vec![("user.name".into(), b"value".to_vec())]);
+        let ToSqlOutput::Owned(rusqlite::types::Value::Blob(bytes)) = xattrs.to_sql().unwrap()
+        else {
+            panic!("not stored as a blob");
+        };
+        for len in 1..bytes.len() {
+            assert!(Xattrs::column_result(ValueRef::Blob(&bytes[..len])).is_err());
+        }
+    }
+}
@@ -0,0 +1,676 @@
+use crate::cache::CacheError;
+
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use zstd::zstd_safe::{self, CCtx, CParameter, DCtx, DParameter};
+
+/// The SHA-256 hash of a blob's content, which identifies it in the cache.
+pub type Hash = [u8; 32];
+
+/// The zstd level blobs are compressed at; low levels are fast and already
+/// shrink text several times over.
+const LEVEL: i32 = 3;
+
+/// Content shorter than this is not worth compressing.
+const MIN_COMPRESS: usize = 64;
+
+/// Every this many versions of a file in a row, one is stored in full rather
+/// than as a delta, so restoring any version replays a bounded chain.
+const KEYFRAME_INTERVAL: i64 = 8;
+
+/// The largest window zstd can address on 64-bit platforms, as a power of two.
+const MAX_WINDOW_LOG: u32 = 31;
+
+/// How many backups and deltas refer to each blob.
+const EXPECTED_REFS: &str = "(SELECT COUNT(*) FROM files WHERE files.blob = blobs.hash)
+    + (SELECT COUNT(*) FROM blobs AS deltas WHERE deltas.base = blobs.hash)";
+
+/// Signatures of formats that are compressed already, as (offset, magic bytes).
+const COMPRESSED: &[(usize, &[u8])] = &[
+    (0, b"\x1f\x8b"),           // gzip
+    (0, b"\x28\xb5\x2f\xfd"),   // zstd
+    (0, b"\xfd7zXZ\x00"),       // xz
+    (0, b"BZh"),                // bzip2
+    (0, b"\x04\x22\x4d\x18"),   // lz4
+    (0, b"7z\xbc\xaf\x27\x1c"), // 7-Zip
+    (0, b"PK\x03\x04"),         // zip, jar, docx and friends
+    (0, b"Rar!\x1a\x07"),       // RAR
+    (0, b"\x89PNG"),            // PNG
+    (0, b"\xff\xd8\xff"),       // JPEG
+    (0, b"GIF8"),               // GIF
+    (8, b"WEBP"),               // WebP
+    (4, b"ftyp"),               // MP4, MOV, HEIC
+    (0, b"OggS"),               // Ogg
+    (0, b"fLaC"),               // FLAC
+    (0, b"\x1a\x45\xdf\xa3"),   // Matroska, WebM
+];
+
+/// How the content of a blob is encoded in the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Stored as is, because it did not compress or was written before
+    /// blobs were compressed.
+    None,
+    Zstd,
+    /// Compressed with zstd using the content of the base blob as a prefix,
+    /// so that it only encodes what changed since that version.
+    ZstdPatch,
+}
+
+impl Codec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::ZstdPatch => "zstd-patch",
+        }
+    }
+
+    pub fn parse(codec: &str) -> Option<Codec> {
+        match codec {
+            "none" => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            "zstd-patch" => Some(Codec::ZstdPatch),
+            _ => None,
+        }
+    }
+}
+
+/// Creates the table holding the content of backed-up files and symlinks.
+///
+/// Each distinct content is stored once, however many backups share it, and
+/// counts the backups referring to it. A blob stored as a delta names its base
+/// and how many deltas deep it is; deltas count as references to their base.
+pub fn create_table(conn: &Connection) -> Result<(), CacheError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blobs (
+            hash BLOB PRIMARY KEY,
+            content BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            codec TEXT NOT NULL DEFAULT 'none',
+            base BLOB REFERENCES blobs (hash),
+            depth INTEGER NOT NULL DEFAULT 0,
+            refs INTEGER NOT NULL DEFAULT 0
+        )",
+        params![],
+    )
+    .map_err(CacheError::Rusqlite)?;
+    Ok(())
+}
+
+/// Creates the triggers that keep the reference counts of blobs up to date as
+/// backups and deltas come and go, and drop blobs nothing refers to any more.
+///
+/// Backups replaced by `INSERT OR REPLACE` only fire the delete trigger with
+/// recursive triggers enabled, so that is done too.
+pub fn create_triggers(conn: &Connection) -> Result<(), CacheError> {
+    conn.execute_batch(
+        "PRAGMA recursive_triggers = ON;
+        CREATE TRIGGER IF NOT EXISTS blobs_ref AFTER INSERT ON files
+        WHEN NEW.blob IS NOT NULL BEGIN
+            UPDATE blobs SET refs = refs + 1 WHERE hash = NEW.blob;
+        END;
+        CREATE TRIGGER IF NOT EXISTS blobs_unref AFTER DELETE ON files
+        WHEN OLD.blob IS NOT NULL BEGIN
+            UPDATE blobs SET refs = refs - 1 WHERE hash = OLD.blob;
+            DELETE FROM blobs WHERE hash = OLD.blob AND refs <= 0;
+        END;
+        CREATE TRIGGER IF NOT EXISTS blobs_base_ref AFTER INSERT ON blobs
+        WHEN NEW.base IS NOT NULL BEGIN
+            UPDATE blobs SET refs = refs + 1 WHERE hash = NEW.base;
+        END;
+        CREATE TRIGGER IF NOT EXISTS blobs_base_unref AFTER DELETE ON blobs
+        WHEN OLD.base IS NOT NULL BEGIN
+            UPDATE blobs SET refs = refs - 1 WHERE hash = OLD.base;
+            DELETE FROM blobs WHERE hash = OLD.base AND refs <= 0;
+        END;",
+    )
+    .map_err(CacheError::Rusqlite)
+}
+
+/// Stores `content` unless an identical blob already exists, and returns its hash.
+///
+/// The content is compressed unless it is in a compressed format already or
+/// does not shrink enough to be worth it. Given the previous version of the
+/// same file as `base`, it is stored as a delta against it when that is
+/// smaller, unless the chain of deltas has reached the keyframe interval. The
+/// hash is always of the original content. The blob is referenced once a
+/// backup refers to it by the hash.
+pub fn store(conn: &Connection, content: &[u8], base: Option<&Hash>) -> Result<Hash, CacheError> {
+    let hash: Hash = Sha256::digest(content).into();
+    let exists = conn
+        .prepare("SELECT 1 FROM blobs WHERE hash = ?")
+        .and_then(|mut stmt| stmt.exists(params![hash]))
+        .map_err(CacheError::Rusqlite)?;
+    if exists {
+        return Ok(hash);
+    }
+
+    let (mut codec, mut encoded) = match compress(content)? {
+        Some(compressed) => (Codec::Zstd, compressed),
+        None => (Codec::None, content.to_vec()),
+    };
+    let mut delta = None;
+    if let Some((base, depth)) = delta_base(conn, base)? {
+        let base_content = load(conn, &base)?;
+        let patch = patch(&base_content, content)?;
+        if patch.len() < encoded.len() {
+            (codec, encoded) = (Codec::ZstdPatch, patch);
+            delta = Some((base, depth + 1));
+        }
+    }
+
+    let (base, depth) = delta.unzip();
+    conn.execute(
+        "INSERT INTO blobs (hash, content, size, codec, base, depth)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            hash,
+            encoded,
+            content.len() as i64,
+            codec.as_str(),
+            base,
+            depth.unwrap_or(0)
+        ],
+    )
+    .map_err(CacheError::Rusqlite)?;
+    Ok(hash)
+}
+
+/// Returns `base` and its depth if a new delta may be based on it.
+fn delta_base(conn: &Connection, base: Option<&Hash>) -> Result<Option<(Hash, i64)>, CacheError> {
+    let Some(base) = base else {
+        return Ok(None);
+    };
+    let depth: i64 = conn
+        .query_row(
+            "SELECT depth FROM blobs WHERE hash = ?",
+            params![base],
+            |row| row.get(0),
+        )
+        .map_err(CacheError::Rusqlite)?;
+    Ok((depth + 1 < KEYFRAME_INTERVAL).then_some((*base, depth)))
+}
+
+/// Loads the content of the blob with the given hash, decompressing it and
+/// replaying any deltas back to the last keyframe.
+pub fn load(conn: &Connection, hash: &Hash) -> Result<Vec<u8>, CacheError> {
+    let (content, size, codec, base): (Vec<u8>, i64, String, Option<Hash>) = conn
+        .query_row(
+            "SELECT content, size, codec, base FROM blobs WHERE hash = ?",
+            params![hash],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .map_err(CacheError::Rusqlite)?;
+
+    match (Codec::parse(&codec), base) {
+        (Some(Codec::None), _) => Ok(content),
+        (Some(Codec::Zstd), _) => {
+            zstd::bulk::decompress(&content, size as usize).map_err(CacheError::Io)
+        }
+        (Some(Codec::ZstdPatch), Some(base)) => {
+            let base_content = load(conn, &base)?;
+            unpatch(&base_content, &content, size as usize)
+        }
+        (Some(Codec::ZstdPatch), None) => Err(CacheError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "delta without a base",
+        ))),
+        (None, _) => Err(CacheError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unknown codec '{}'", codec),
+        ))),
+    }
+}
+
+/// Compresses `content` if that is worthwhile.
+///
+/// Returns `None` for short content, formats that are compressed already, and
+/// content that shrinks by less than a tenth.
+fn compress(content: &[u8]) -> Result<Option<Vec<u8>>, CacheError> {
+    let compressed_format = COMPRESSED.iter().any(|(offset, magic)| {
+        content
+            .get(*offset..)
+            .is_some_and(|rest| rest.starts_with(magic))
+    });
+    if content.len() < MIN_COMPRESS || compressed_format {
+        return Ok(None);
+    }
+
+    let compressed = zstd::bulk::compress(content, LEVEL).map_err(CacheError::Io)?;
+    Ok((compressed.len() < content.len() - content.len() / 10).then_some(compressed))
+}
+
+/// Compresses `content` against `base`, which it is expected to resemble.
+///
+/// This is zstd's patch-from mode: the window spans both, so that anything
+/// unchanged is encoded as a reference back into `base`.
+fn patch(base: &[u8], content: &[u8]) -> Result<Vec<u8>, CacheError> {
+    let mut cctx = CCtx::create();
+    cctx.set_parameter(CParameter::CompressionLevel(LEVEL))
+        .and_then(|_| cctx.set_parameter(CParameter::WindowLog(window_log(base, content))))
+        .and_then(|_| cctx.set_parameter(CParameter::EnableLongDistanceMatching(true)))
+        .and_then(|_| cctx.ref_prefix(base))
+        .map_err(zstd_error)?;
+
+    let mut patch = Vec::with_capacity(zstd_safe::compress_bound(content.len()));
+    cctx.compress2(&mut patch, content).map_err(zstd_error)?;
+    Ok(patch)
+}
+
+/// Reverses `patch`, returning the `size` bytes of content it encodes.
+fn unpatch(base: &[u8], patch: &[u8], size: usize) -> Result<Vec<u8>, CacheError> {
+    let mut dctx = DCtx::create();
+    dctx.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG))
+        .and_then(|_| dctx.ref_prefix(base))
+        .map_err(zstd_error)?;
+
+    let mut content = Vec::with_capacity(size);
+    dctx.decompress(&mut content, patch).map_err(zstd_error)?;
+    Ok(content)
+}
+
+/// Returns the window size, as a power of two, that covers `base` followed by
+/// `content`.
+fn window_log(base: &[u8], content: &[u8]) -> u32 {
+    let span = (base.len() + content.len()).max(1 << 10);
+    span.next_power_of_two()
+        .trailing_zeros()
+        .min(MAX_WINDOW_LOG)
+}
+
+/// Converts a zstd error code to an error.
+fn zstd_error(code: zstd_safe::ErrorCode) -> CacheError {
+    CacheError::Io(io::Error::other(zstd_safe::get_error_name(code)))
+}
+
+/// The outcome of checking every blob in the cache.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// How many blobs were checked.
+    pub blobs: usize,
+    /// How many of them are stored as deltas.
+    pub deltas: usize,
+    /// The blobs that are damaged.
+    pub damaged: Vec<Damaged>,
+}
+
+/// A blob that does not reconstruct to the content it was stored from, or
+/// whose reference count is off.
+#[derive(Debug)]
+pub struct Damaged {
+    pub hash: Hash,
+    pub problem: String,
+    /// The backed-up paths whose versions use the blob.
+    pub paths: Vec<PathBuf>,
+}
+
+/// Reconstructs every blob, replaying its chain of deltas, and checks that it
+/// hashes to the content it was stored from. Reference counts are checked too.
+pub fn verify(conn: &Connection) -> Result<Verification, CacheError> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT hash, size, codec, refs, {} FROM blobs",
+            EXPECTED_REFS
+        ))
+        .map_err(CacheError::Rusqlite)?;
+    let blobs = stmt
+        .query_map(params![], |row| {
+            Ok((
+                row.get::<_, Hash>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })
+        .map_err(CacheError::Rusqlite)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CacheError::Rusqlite)?;
+
+    let mut verification = Verification::default();
+    for (hash, size, codec, refs, expected) in blobs {
+        verification.blobs += 1;
+        if Codec::parse(&codec) == Some(Codec::ZstdPatch) {
+            verification.deltas += 1;
+        }
+
+        let problem = match load(conn, &hash) {
+            Err(e) => Some(format!("cannot be reconstructed: {}", e)),
+            Ok(content) if content.len() as i64 != size => Some(format!(
+                "reconstructs to {} bytes instead of {}",
+                content.len(),
+                size
+            )),
+            Ok(content) if Sha256::digest(&content)[..] != hash[..] => {
+                Some("reconstructs to different content".to_string())
+            }
+            Ok(_) if refs != expected => Some(format!(
+                "has {} references recorded but {} in use",
+                refs, expected
+            )),
+            Ok(_) => None,
+        };
+        if let Some(problem) = problem {
+            verification.damaged.push(Damaged {
+                hash,
+                problem,
+                paths: paths(conn, &hash)?,
+            });
+        }
+    }
+    Ok(verification)
+}
+
+/// Returns the backed-up paths whose versions use the blob with the given hash.
+fn paths(conn: &Connection, hash: &Hash) -> Result<Vec<PathBuf>, CacheError> {
+    let mut stmt = conn
+        .prepare("SELECT DISTINCT path FROM files WHERE blob = ? ORDER BY path")
+        .map_err(CacheError::Rusqlite)?;
+    let rows = stmt
+        .query_map(params![hash], |row| {
+            let path: Vec<u8> = row.get(0)?;
+            Ok(PathBuf::from(OsString::from_vec(path)))
+        })
+        .map_err(CacheError::Rusqlite)?;
+    rows.collect::<Result<Vec<_>, _>>()
+        .map_err(CacheError::Rusqlite)
+}
+
+/// Moves the content that older databases stored inline in the files table
+/// into blobs, and drops the column it was stored in.
+pub fn migrate_inline(conn: &Connection) -> Result<(), CacheError> {
+    let tx = conn.unchecked_transaction().map_err(CacheError::Rusqlite)?;
+    {
+        let mut stmt = tx
+            .prepare("SELECT id, content FROM files WHERE content IS NOT NULL")
+            .map_err(CacheError::Rusqlite)?;
+        let mut rows = stmt.query(params![]).map_err(CacheError::Rusqlite)?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0).map_err(CacheError::Rusqlite)?;
+            let content: Vec<u8> = row.get(1).map_err(CacheError::Rusqlite)?;
+            let hash = store(&tx, &content, None)?;
+            tx.execute(
+                "UPDATE files SET blob = ?1 WHERE id = ?2",
+                params![hash, id],
+            )
+            .map_err(CacheError::Rusqlite)?;
+        }
+    }
+    tx.execute_batch(&format!(
+        "UPDATE blobs SET refs = {};
+        ALTER TABLE files DROP COLUMN content;",
+        EXPECTED_REFS
+    ))
+    .map_err(CacheError::Rusqlite)?;
+    tx.commit().map_err(CacheError::Rusqlite)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opens an empty cache holding just what blobs need of the files table.
+    fn connection() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE files (
+                id INTEGER PRIMARY KEY,
+                session INTEGER NOT NULL,
+                path BLOB NOT NULL,
+                blob BLOB,
+                UNIQUE (session, path)
+            )",
+        )
+        .unwrap();
+        create_table(&conn).unwrap();
+        create_triggers(&conn).unwrap();
+        conn
+    }
+
+    /// Backs `path` up with the blob `hash`, replacing any earlier backup in `session`.
+    fn back_up(conn: &Connection, session: i64, path: &str, hash: &Hash) {
+        conn.execute(
+            "INSERT OR REPLACE INTO files (session, path, blob) VALUES (?1, ?2, ?3)",
+            params![session, path.as_bytes(), hash],
+        )
+        .unwrap();
+    }
+
+    fn refs(conn: &Connection, hash: &Hash) -> Option<i64> {
+        conn.query_row(
+            "SELECT refs FROM blobs WHERE hash = ?",
+            params![hash],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    fn codec(conn: &Connection, hash: &Hash) -> Codec {
+        let codec: String = conn
+            .query_row(
+                "SELECT codec FROM blobs WHERE hash = ?",
+                params![hash],
+                |row| row.get(0),
+            )
+            .unwrap();
+        Codec::parse(&codec).unwrap()
+    }
+
+    /// Content that zstd cannot shrink on its own, so only deltas pay off.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_and_dedup() {
+        let conn = connection();
+        let text = b"the same line over and over\n".repeat(100);
+
+        let hash = store(&conn, &text, None).unwrap();
+        assert_eq!(hash, <Hash>::from(Sha256::digest(&text)));
+        assert_eq!(load(&conn, &hash).unwrap(), text);
+
+        assert_eq!(store(&conn, &text, None).unwrap(), hash);
+        let blobs: i64 = conn
+            .query_row("SELECT COUNT(*) FROM blobs", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(blobs, 1);
+
+        let empty = store(&conn, b"", None).unwrap();
+        assert_eq!(load(&conn, &empty).unwrap(), b"");
+    }
+
+    #[test]
+    fn codec_fallbacks() {
+        let conn = connection();
+
+        let short = store(&conn, b"short", None).unwrap();
+        assert_eq!(codec(&conn, &short), Codec::None);
+
+        let text = b"compressible ".repeat(100);
+        let compressible = store(&conn, &text, None).unwrap();
+        assert_eq!(codec(&conn, &compressible), Codec::Zstd);
+
+        let gzip = [&b"\x1f\x8b"[..], &text].concat();
+        let compressed = store(&conn, &gzip, None).unwrap();
+        assert_eq!(codec(&conn, &compressed), Codec::None);
+
+        let random = noise(4096, 1);
+        let incompressible = store(&conn, &random, None).unwrap();
+        assert_eq!(codec(&conn, &incompressible), Codec::None);
+        assert_eq!(load(&conn, &incompressible).unwrap(), random);
+
+        conn.execute(
+            "UPDATE blobs SET codec = 'lzma' WHERE hash = ?",
+            params![short],
+        )
+        .unwrap();
+        assert!(load(&conn, &short).is_err());
+        conn.execute(
+            "UPDATE blobs SET codec = 'zstd-patch' WHERE hash = ?",
+            params![compressible],
+        )
+        .unwrap();
+        assert!(load(&conn, &compressible).is_err());
+    }
+
+    #[test]
+    fn refcounts_follow_backups() {
+        let conn = connection();
+        let a = store(&conn, b"version a", None).unwrap();
+        let b = store(&conn, b"version b", None).unwrap();
+        assert_eq!(refs(&conn, &a), Some(0));
+
+        back_up(&conn, 1, "/f", &a);
+        back_up(&conn, 2, "/f", &a);
+        assert_eq!(refs(&conn, &a), Some(2));
+
+        // Replacing a backup releases the blob it used.
+        back_up(&conn, 2, "/f", &b);
+        assert_eq!(refs(&conn, &a), Some(1));
+        assert_eq!(refs(&conn, &b), Some(1));
+
+        conn.execute("DELETE FROM files WHERE session = 1", params![])
+            .unwrap();
+        assert_eq!(refs(&conn, &a), None);
+        assert_eq!(refs(&conn, &b), Some(1));
+    }
+
+    #[test]
+    fn deltas_keep_their_base() {
+        let conn = connection();
+        let v1 = noise(1 << 16, 2);
+        let mut v2 = v1.clone();
+        v2[100..108].copy_from_slice(b"changed!");
+
+        let h1 = store(&conn, &v1, None).unwrap();
+        back_up(&conn, 1, "/f", &h1);
+        let h2 = store(&conn, &v2, Some(&h1)).unwrap();
+        back_up(&conn, 2, "/f", &h2);
+        assert_eq!(codec(&conn, &h2), Codec::ZstdPatch);
+        assert_eq!(refs(&conn, &h1), Some(2));
+
+        conn.execute("DELETE FROM files WHERE session = 1", params![])
+            .unwrap();
+        assert_eq!(refs(&conn, &h1), Some(1));
+        assert_eq!(load(&conn, &h2).unwrap(), v2);
+
+        // Dropping the delta drops the base along with it.
+        conn.execute("DELETE FROM files WHERE session = 2", params![])
+            .unwrap();
+        assert_eq!(refs(&conn, &h2), None);
+        assert_eq!(refs(&conn, &h1), None);
+    }
+
+    #[test]
+    fn deltas_restart_at_keyframes() {
+        let conn = connection();
+        let mut content = noise(1 << 16, 3);
+        let mut versions = Vec::new();
+        let mut base = None;
+        for i in 0..(2 * KEYFRAME_INTERVAL as usize + 2) {
+            content[i * 64] ^= 0xff;
+            let hash = store(&conn, &content, base.as_ref()).unwrap();
+            versions.push((hash, content.clone()));
+            base = Some(hash);
+        }
+
+        for (i, (hash, content)) in versions.iter().enumerate() {
+            let depth: i64 = conn
+                .query_row(
+                    "SELECT depth FROM blobs WHERE hash = ?",
+                    params![hash],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(depth, i as i64 % KEYFRAME_INTERVAL);
+            let expected = if depth == 0 {
+                Codec::None
+            } else {
+                Codec::ZstdPatch
+            };
+            assert_eq!(codec(&conn, hash), expected);
+            assert_eq!(&load(&conn, hash).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn migrate_inline_moves_content_into_blobs() {
+        let conn = connection();
+        conn.execute_batch("ALTER TABLE files ADD COLUMN content BLOB")
+            .unwrap();
+        for (session, content) in [(1, &b"same"[..]), (2, b"same"), (3, b"other")] {
+            conn.execute(
+                "INSERT INTO files (session, path, content) VALUES (?1, '/f', ?2)",
+                params![session, content],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO files (session, path) VALUES (4, '/dir')",
+            params![],
+        )
+        .unwrap();
+
+        migrate_inline(&conn).unwrap();
+
+        let same: Hash = Sha256::digest(b"same").into();
+        let other: Hash = Sha256::digest(b"other").into();
+        assert_eq!(refs(&conn, &same), Some(2));
+        assert_eq!(refs(&conn, &other), Some(1));
+        assert_eq!(load(&conn, &same).unwrap(), b"same");
+        assert!(conn.prepare("SELECT content FROM files").is_err());
+        assert!(verify(&conn).unwrap().damaged.is_empty());
+    }
+
+    #[test]
+    fn verify_flags_damage() {
+        let conn = connection();
+        let v1 = noise(1 << 14, 4);
+        let mut v2 = v1.clone();
+        v2[0] ^= 0xff;
+        let h1 = store(&conn, &v1, None).unwrap();
+        back_up(&conn, 1, "/f", &h1);
+        let h2 = store(&conn, &v2, Some(&h1)).unwrap();
+        back_up(&conn, 2, "/f", &h2);
+
+        let verification = verify(&conn).unwrap();
+        assert_eq!(verification.blobs, 2);
+        assert_eq!(verification.deltas, 1);
+        assert!(verification.damaged.is_empty());
+
+        // Corrupting the keyframe breaks the delta built on it too.
+        conn.execute(
+            "UPDATE blobs SET content = ?1 WHERE hash = ?2",
+            params![noise(1 << 14, 5), h1],
+        )
+        .unwrap();
+        let damaged = verify(&conn).unwrap().damaged;
+        assert_eq!(damaged.len(), 2);
+        assert!(damaged
+            .iter()
+            .all(|damaged| damaged.paths == [PathBuf::from("/f")]));
+
+        let conn = connection();
+        let hash = store(&conn, b"counted", None).unwrap();
+        back_up(&conn, 1, "/f", &hash);
+        conn.execute("UPDATE blobs SET refs = 5", params![])
+            .unwrap();
+        let damaged = verify(&conn).unwrap().damaged;
+        assert_eq!(damaged.len(), 1);
+        assert!(damaged[0].problem.contains("references"));
+    }
+}
@@ -1,10 +1,15 @@
-use rusqlite::{params, Connection, Error as RusqliteError};
+use crate::cache::{blob, now, Attributes, Hash, Kind, Rename, Session, Verification, Version};
+
+use nix::fcntl::{renameat2, RenameFlags};
+use rusqlite::{params, Connection, Error as RusqliteError, OptionalExtension, Transaction};
 use std::env;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{self as unix_fs, MetadataExt};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
@@ -12,6 +17,13 @@ pub enum CacheError {
     Io(io::Error),
     Rusqlite(RusqliteError),
     FileNotFound(PathBuf),
+    /// The path has fewer versions than the one asked for.
+    VersionNotFound(PathBuf, usize),
+    /// No backup of the path was taken after the time asked for.
+    NoChangesSince(PathBuf),
+    /// A later session also changed the path, so restoring it would undo that
+    /// session's changes too.
+    ChangedLater(PathBuf, i64),
 }
 
 impl fmt::Display for CacheError {
@@ -22,6 +34,20 @@ impl fmt::Display for CacheError {
             CacheError::FileNotFound(path) => {
                 write!(f, "File not found in cache: {}", path.display())
             }
+            CacheError::VersionNotFound(path, version) => {
+                write!(f, "No version {} of {} in cache", version, path.display())
+            }
+            CacheError::NoChangesSince(path) => {
+                write!(f, "No changes to {} recorded since then", path.display())
+            }
+            CacheError::ChangedLater(path, session) => {
+                write!(
+                    f,
+                    "{} was changed again by session {}; revert that session first",
+                    path.display(),
+                    session
+                )
+            }
         }
     }
 }
@@ -40,6 +66,37 @@ impl From<RusqliteError> for CacheError {
     }
 }
 
+/// Something `Cache::restore` could not bring back exactly as it was.
+#[derive(Debug)]
+pub enum RestoreWarning {
+    /// The file shared its inode with other hard links, none of which could be
+    /// found, so it was restored as a file of its own.
+    LinksLost { path: PathBuf, links: u64 },
+    /// The path is back, but its mode, owner, extended attributes or timestamps
+    /// could not be set, since that takes ownership or privileges the caller lacks.
+    AttributesLost { path: PathBuf, error: io::Error },
+}
+
+impl fmt::Display for RestoreWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreWarning::LinksLost { path, links } => write!(
+                f,
+                "{} had {} hard links, but none of the others could be found; \
+                it was restored as a separate file",
+                path.display(),
+                links
+            ),
+            RestoreWarning::AttributesLost { path, error } => write!(
+                f,
+                "could not restore the attributes of {}: {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Cache {
     conn: Connection,
@@ -54,125 +111,1796 @@ impl Cache {
             Ok(home) => PathBuf::from(home).join(".cache").join("undo"),
             Err(_) => PathBuf::from("~/.cache/undo"),
         };
-        fs::create_dir_all(&cache_dir).map_err(|e| CacheError::Io(e))?;
+        fs::create_dir_all(&cache_dir).map_err(CacheError::Io)?;
+        Cache::open(&cache_dir.join("cache.db"))
+    }
 
-        let conn =
-            Connection::open(cache_dir.join("cache.db")).map_err(|e| CacheError::Rusqlite(e))?;
+    /// Open the cache database at `path`, creating or upgrading it as needed.
+    pub fn open(path: &Path) -> Result<Self, CacheError> {
+        let conn = Connection::open(path).map_err(CacheError::Rusqlite)?;
 
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS files (
+            "CREATE TABLE IF NOT EXISTS sessions (
                 id INTEGER PRIMARY KEY,
-                path TEXT NOT NULL UNIQUE,
-                content BLOB,
-                permissions INTEGER
+                command BLOB NOT NULL,
+                cwd BLOB NOT NULL,
+                pid INTEGER,
+                started INTEGER NOT NULL,
+                ended INTEGER,
+                status INTEGER
             )",
             params![],
         )
-        .map_err(|e| CacheError::Rusqlite(e))?;
+        .map_err(CacheError::Rusqlite)?;
+        blob::create_table(&conn)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS renames (
+                id INTEGER PRIMARY KEY,
+                session INTEGER NOT NULL REFERENCES sessions (id),
+                source BLOB NOT NULL,
+                target BLOB NOT NULL,
+                exchange INTEGER NOT NULL,
+                taken INTEGER NOT NULL
+            )",
+            params![],
+        )
+        .map_err(CacheError::Rusqlite)?;
+        conn.execute(&files_schema("IF NOT EXISTS files"), params![])
+            .map_err(CacheError::Rusqlite)?;
+
+        // Older databases only stored regular files and their permissions.
+        add_column(&conn, "files", "kind", "TEXT NOT NULL DEFAULT 'file'")?;
+        add_column(&conn, "files", "uid", "INTEGER")?;
+        add_column(&conn, "files", "gid", "INTEGER")?;
+        add_column(&conn, "files", "atime", "INTEGER")?;
+        add_column(&conn, "files", "mtime", "INTEGER")?;
+        add_column(&conn, "files", "xattrs", "BLOB")?;
+        add_column(&conn, "files", "dev", "INTEGER")?;
+        add_column(&conn, "files", "ino", "INTEGER")?;
+        add_column(&conn, "files", "nlink", "INTEGER")?;
+        add_column(&conn, "files", "links", "BLOB")?;
+        add_column(&conn, "files", "taken", "INTEGER")?;
+        add_column(&conn, "files", "blob", "BLOB REFERENCES blobs (hash)")?;
+        // Older databases did not record which process changed each path.
+        add_column(&conn, "files", "pid", "INTEGER")?;
+        // Blobs were stored uncompressed before they recorded a codec.
+        add_column(&conn, "blobs", "codec", "TEXT NOT NULL DEFAULT 'none'")?;
+        // Blobs were stored whole before they could be deltas against an earlier version.
+        add_column(&conn, "blobs", "base", "BLOB REFERENCES blobs (hash)")?;
+        add_column(&conn, "blobs", "depth", "INTEGER NOT NULL DEFAULT 0")?;
+
+        // Paths are stored as raw bytes so that non-UTF-8 names round-trip;
+        // older databases stored them as text.
+        conn.execute(
+            "UPDATE files SET path = CAST(path AS BLOB) WHERE typeof(path) = 'text'",
+            params![],
+        )
+        .map_err(CacheError::Rusqlite)?;
+
+        // Older databases stored the content of every backup inline.
+        if has_column(&conn, "files", "content")? {
+            blob::migrate_inline(&conn)?;
+        }
+        // Older databases kept a single backup per path, outside any session.
+        if !has_column(&conn, "files", "session")? {
+            migrate_to_sessions(&conn)?;
+        }
+        blob::create_triggers(&conn)?;
         Ok(Cache { conn })
     }
 
-    /// Check if a file is being tracked.
-    pub fn is_tracked(&self, file_path: &Path) -> Result<bool, CacheError> {
+    /// Record the start of a run of `command` in `cwd` as process `pid`.
+    ///
+    /// Returns the id of the new session, which its backups are filed under.
+    pub fn start_session(
+        &self,
+        command: &[OsString],
+        cwd: &Path,
+        pid: u32,
+    ) -> Result<i64, CacheError> {
+        self.conn
+            .execute(
+                "INSERT INTO sessions (command, cwd, pid, started) VALUES (?1, ?2, ?3, ?4)",
+                params![encode_args(command), cwd.as_os_str().as_bytes(), pid, now()],
+            )
+            .map_err(CacheError::Rusqlite)?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record the end of a session, with the program's exit status if known.
+    pub fn end_session(&self, session: i64, status: Option<i32>) -> Result<(), CacheError> {
+        self.conn
+            .execute(
+                "UPDATE sessions SET ended = ?1, status = ?2 WHERE id = ?3",
+                params![now(), status, session],
+            )
+            .map_err(CacheError::Rusqlite)?;
+        Ok(())
+    }
+
+    /// Get the sessions that still have backups, oldest first.
+    pub fn sessions(&self) -> Result<Vec<Session>, CacheError> {
         let mut stmt = self
             .conn
-            .prepare("SELECT 1 FROM files WHERE path = ?")
-            .map_err(|e| CacheError::Rusqlite(e))?;
-        let mut rows = stmt
-            .query(params![file_path.to_string_lossy()])
-            .map_err(|e| CacheError::Rusqlite(e))?;
-        Ok(rows.next()?.is_some())
+            .prepare(
+                "SELECT id, command, cwd, pid, started, ended, status FROM sessions
+                WHERE EXISTS (
+                    SELECT 1 FROM files WHERE files.session = sessions.id AND kind != 'times'
+                )
+                ORDER BY id",
+            )
+            .map_err(CacheError::Rusqlite)?;
+
+        let rows = stmt
+            .query_map(params![], |row| {
+                let command: Vec<u8> = row.get(1)?;
+                let cwd: Vec<u8> = row.get(2)?;
+                Ok(Session {
+                    id: row.get(0)?,
+                    command: decode_args(&command),
+                    cwd: PathBuf::from(OsString::from_vec(cwd)),
+                    pid: row.get::<_, Option<u32>>(3)?.unwrap_or_default(),
+                    started: row.get(4)?,
+                    ended: row.get(5)?,
+                    status: row.get(6)?,
+                })
+            })
+            .map_err(CacheError::Rusqlite)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(CacheError::Rusqlite)
     }
 
-    /// Backup a file.
-    pub fn backup(&self, file_path: &Path) -> Result<(), CacheError> {
-        let content = fs::read(file_path).map_err(|e| CacheError::Io(e))?;
-        let metadata = fs::metadata(file_path).map_err(|e| CacheError::Io(e))?;
-        let permissions = metadata.permissions().mode();
+    /// Backup a file, directory or symlink before process `pid` of `session`
+    /// changes it, with its mode, ownership, timestamps and extended attributes.
+    ///
+    /// A path that does not exist yet is recorded as a tombstone, so that
+    /// whatever gets created there can be removed again.
+    pub fn backup(&self, session: i64, pid: u32, file_path: &Path) -> Result<(), CacheError> {
+        let metadata = match fs::symlink_metadata(file_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.conn
+                    .execute(
+                        "INSERT OR REPLACE INTO files (session, path, kind, taken, pid)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            session,
+                            file_path.as_os_str().as_bytes(),
+                            Kind::Absent.as_str(),
+                            now(),
+                            pid
+                        ],
+                    )
+                    .map_err(CacheError::Rusqlite)?;
+                return Ok(());
+            }
+            Err(err) => return Err(CacheError::Io(err)),
+        };
+
+        // A symlink's target is saved rather than followed; a directory only
+        // needs its metadata to be recreated.
+        let (kind, content) = if metadata.is_symlink() {
+            let target = fs::read_link(file_path).map_err(CacheError::Io)?;
+            (Kind::Symlink, Some(target.into_os_string().into_vec()))
+        } else if metadata.is_dir() {
+            (Kind::Directory, None)
+        } else {
+            let content = fs::read(file_path).map_err(CacheError::Io)?;
+            (Kind::File, Some(content))
+        };
+
+        let mut attributes = Attributes::read(file_path, &metadata).map_err(CacheError::Io)?;
+        if kind == Kind::Symlink {
+            // A symlink's mode is meaningless and cannot be changed.
+            attributes.permissions = None;
+        }
 
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(CacheError::Rusqlite)?;
+        let previous: Option<Hash> = tx
+            .query_row(
+                "SELECT blob FROM files WHERE path = ? AND blob IS NOT NULL
+                ORDER BY id DESC LIMIT 1",
+                params![file_path.as_os_str().as_bytes()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(CacheError::Rusqlite)?;
+        // Stored once however many backups share it, and as a delta against the
+        // path's previous version where that helps.
+        let blob = content
+            .map(|content| blob::store(&tx, &content, previous.as_ref()))
+            .transpose()?;
+        // If the session only touched the entries of this directory so far, the
+        // times it saved then are the original ones.
+        let times: Option<(Option<i64>, Option<i64>)> = tx
+            .query_row(
+                "SELECT atime, mtime FROM files WHERE session = ?1 AND path = ?2 AND kind = ?3",
+                params![
+                    session,
+                    file_path.as_os_str().as_bytes(),
+                    Kind::Times.as_str()
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(CacheError::Rusqlite)?;
+        if let Some((atime, mtime)) = times {
+            (attributes.atime, attributes.mtime) = (atime, mtime);
+        }
+        // The device, inode, link count and the inode's other names in the
+        // directory let restore rejoin hard links.
+        tx.execute(
+            "INSERT OR REPLACE INTO files
+                (session, path, blob, permissions, kind, uid, gid, atime, mtime, xattrs,
+                dev, ino, nlink, links, taken, pid)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                session,
+                file_path.as_os_str().as_bytes(),
+                blob,
+                attributes.permissions,
+                kind.as_str(),
+                attributes.uid,
+                attributes.gid,
+                attributes.atime,
+                attributes.mtime,
+                attributes.xattrs,
+                metadata.dev() as i64,
+                metadata.ino() as i64,
+                metadata.nlink() as i64,
+                encode_names(&link_names(file_path, &metadata)),
+                now(),
+                pid
+            ],
+        )
+        .map_err(CacheError::Rusqlite)?;
+        tx.commit().map_err(CacheError::Rusqlite)
+    }
+
+    /// Backup only the timestamps of a directory before process `pid` of
+    /// `session` adds or removes entries in it.
+    ///
+    /// Nothing is recorded if the session has backed the directory up already.
+    /// Returns whether a backup was taken.
+    pub fn backup_times(&self, session: i64, pid: u32, dir: &Path) -> Result<bool, CacheError> {
+        let metadata = fs::symlink_metadata(dir).map_err(CacheError::Io)?;
+        if !metadata.is_dir() {
+            return Ok(false);
+        }
+        let attributes = Attributes::of(&metadata);
+        let inserted = self
+            .conn
+            .execute(
+                "INSERT OR IGNORE INTO files (session, path, kind, atime, mtime, taken, pid)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    session,
+                    dir.as_os_str().as_bytes(),
+                    Kind::Times.as_str(),
+                    attributes.atime,
+                    attributes.mtime,
+                    now(),
+                    pid
+                ],
+            )
+            .map_err(CacheError::Rusqlite)?;
+        Ok(inserted > 0)
+    }
+
+    /// Remove a session's backup of a file without restoring it.
+    pub fn discard(&self, session: i64, file_path: &Path) -> Result<(), CacheError> {
         self.conn
             .execute(
-                "INSERT OR REPLACE INTO files (path, content, permissions) VALUES (?1, ?2, ?3)",
-                params![file_path.to_string_lossy(), content, permissions],
+                "DELETE FROM files WHERE session = ?1 AND path = ?2",
+                params![session, file_path.as_os_str().as_bytes()],
             )
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .map_err(CacheError::Rusqlite)?;
         Ok(())
     }
 
-    /// Clear the entire cache by deleting all records in the files table.
+    /// Clear the entire cache by deleting all backups and sessions.
     pub fn clear(&mut self) -> Result<(), CacheError> {
-        let tx = self
-            .conn
-            .transaction()
-            .map_err(|e| CacheError::Rusqlite(e))?;
+        let tx = self.conn.transaction().map_err(CacheError::Rusqlite)?;
 
         tx.execute("DELETE FROM files", params![])
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .map_err(CacheError::Rusqlite)?;
+        tx.execute("DELETE FROM sessions", params![])
+            .map_err(CacheError::Rusqlite)?;
+        tx.execute("DELETE FROM blobs", params![])
+            .map_err(CacheError::Rusqlite)?;
+        tx.execute("DELETE FROM renames", params![])
+            .map_err(CacheError::Rusqlite)?;
 
-        tx.commit().map_err(|e| CacheError::Rusqlite(e))?;
+        tx.commit().map_err(CacheError::Rusqlite)?;
+        Ok(())
+    }
+
+    /// Record that `session` renamed a path, once the rename has succeeded.
+    ///
+    /// Both paths are backed up as usual beforehand; this only records the
+    /// move itself, so that a revert can move the file or tree back.
+    pub fn record_rename(&self, session: i64, rename: &Rename) -> Result<(), CacheError> {
+        self.conn
+            .execute(
+                "INSERT INTO renames (session, source, target, exchange, taken)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    session,
+                    rename.from.as_os_str().as_bytes(),
+                    rename.to.as_os_str().as_bytes(),
+                    rename.exchange,
+                    now()
+                ],
+            )
+            .map_err(CacheError::Rusqlite)?;
         Ok(())
     }
 
-    /// Get a list of all files tracked in the cache.
-    pub fn list(&self) -> Result<Vec<PathBuf>, CacheError> {
+    /// Undo recorded renames, newest first, by moving each path back.
+    ///
+    /// With a `session`, only its renames are undone; with `since`, only those
+    /// made at or after that time; with a `path`, only those from or to it.
+    /// This comes before restoring the backups of the paths involved: the
+    /// source then gets its content and children back as they are, and the
+    /// destination can be restored to what it held before. Backups taken after
+    /// a rename under its destination are moved to the source along with it.
+    pub fn undo_renames(
+        &mut self,
+        session: Option<i64>,
+        since: Option<i64>,
+        path: Option<&Path>,
+    ) -> Result<Vec<Rename>, CacheError> {
+        let renames = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT id, source, target, exchange, taken FROM renames
+                    WHERE (?1 IS NULL OR session = ?1) AND (?2 IS NULL OR taken >= ?2)
+                    AND (?3 IS NULL OR source = ?3 OR target = ?3)
+                    ORDER BY id DESC",
+                )
+                .map_err(CacheError::Rusqlite)?;
+            let rows = stmt
+                .query_map(
+                    params![session, since, path.map(|path| path.as_os_str().as_bytes())],
+                    |row| {
+                        let from: Vec<u8> = row.get(1)?;
+                        let to: Vec<u8> = row.get(2)?;
+                        let rename = Rename {
+                            from: PathBuf::from(OsString::from_vec(from)),
+                            to: PathBuf::from(OsString::from_vec(to)),
+                            exchange: row.get(3)?,
+                        };
+                        Ok((row.get::<_, i64>(0)?, rename, row.get::<_, i64>(4)?))
+                    },
+                )
+                .map_err(CacheError::Rusqlite)?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(CacheError::Rusqlite)?
+        };
+
+        let mut undone = Vec::new();
+        for (id, rename, taken) in renames {
+            let tx = self.conn.transaction().map_err(CacheError::Rusqlite)?;
+            move_back(&rename)?;
+            remap_backups(&tx, &rename, taken)?;
+            tx.execute("DELETE FROM renames WHERE id = ?", params![id])
+                .map_err(CacheError::Rusqlite)?;
+            tx.commit().map_err(CacheError::Rusqlite)?;
+            undone.push(rename);
+        }
+        Ok(undone)
+    }
+
+    /// Get a list of the files a session changed.
+    pub fn list(&self, session: i64) -> Result<Vec<PathBuf>, CacheError> {
         let mut stmt = self
             .conn
-            .prepare("SELECT path FROM files")
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .prepare("SELECT path FROM files WHERE session = ?1 AND kind != ?2 ORDER BY path")
+            .map_err(CacheError::Rusqlite)?;
 
         let rows = stmt
-            .query_map(params![], |row| {
-                let path: String = row.get(0)?;
-                Ok(PathBuf::from(path))
+            .query_map(params![session, Kind::Times.as_str()], |row| {
+                let path: Vec<u8> = row.get(0)?;
+                Ok(PathBuf::from(OsString::from_vec(path)))
             })
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .map_err(CacheError::Rusqlite)?;
 
         rows.collect::<Result<Vec<_>, _>>()
-            .map_err(|e| CacheError::Rusqlite(e))
+            .map_err(CacheError::Rusqlite)
     }
 
-    /// Restore a file and remove it from the cache database.
-    pub fn restore(&mut self, file_path: &Path) -> Result<(), CacheError> {
-        let tx = self
+    /// Get the tracked paths in the order they can safely be restored one by one.
+    ///
+    /// Paths that did not exist come first, children before parents, so that
+    /// created trees can be removed bottom-up. The remaining paths follow with
+    /// parents before children, so that deleted trees are recreated top-down.
+    ///
+    /// With a `session`, only the paths it changed are returned, and with
+    /// `since`, only the paths backed up at or after that time. Each path is
+    /// classified by the oldest backup that matches.
+    pub fn restore_order(
+        &self,
+        session: Option<i64>,
+        since: Option<i64>,
+    ) -> Result<Vec<PathBuf>, CacheError> {
+        // SQLite takes the bare columns from the row holding the minimum.
+        let mut stmt = self
             .conn
-            .transaction()
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .prepare(&format!(
+                "SELECT path, kind, MIN(files.id) FROM {}
+                WHERE (?1 IS NULL OR session = ?1) AND (?2 IS NULL OR {} >= ?2)
+                GROUP BY path",
+                WITH_SESSIONS, TAKEN
+            ))
+            .map_err(CacheError::Rusqlite)?;
+
+        let rows = stmt
+            .query_map(params![session, since], |row| {
+                let path: Vec<u8> = row.get(0)?;
+                let kind: String = row.get(1)?;
+                Ok((PathBuf::from(OsString::from_vec(path)), Kind::parse(&kind)))
+            })
+            .map_err(CacheError::Rusqlite)?;
+
+        let (mut absent, mut present): (Vec<_>, Vec<_>) = rows
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(CacheError::Rusqlite)?
+            .into_iter()
+            .partition(|(_, kind)| *kind == Kind::Absent);
+
+        // Paths compare component by component, so a parent sorts before its children.
+        absent.sort_by(|(a, _), (b, _)| b.cmp(a));
+        present.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(absent
+            .into_iter()
+            .chain(present)
+            .map(|(path, _)| path)
+            .collect())
+    }
+
+    /// Restore a file to its oldest version, from before any tracked run
+    /// changed it, and remove its whole history from the cache database.
+    ///
+    /// Whatever cannot be brought back exactly, such as hard links or
+    /// attributes, is returned as warnings.
+    pub fn restore(&mut self, file_path: &Path) -> Result<Vec<RestoreWarning>, CacheError> {
+        let first = self
+            .conn
+            .query_row(
+                "SELECT MIN(id) FROM files WHERE path = ?",
+                params![file_path.as_os_str().as_bytes()],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .map_err(CacheError::Rusqlite)?
+            .ok_or_else(|| CacheError::FileNotFound(file_path.to_path_buf()))?;
+        self.restore_from(file_path, first)
+    }
+
+    /// Restore a file to the given version of its history, numbered from 1 as
+    /// returned by `history`, and remove that version and every later one.
+    pub fn restore_version(
+        &mut self,
+        file_path: &Path,
+        version: usize,
+    ) -> Result<Vec<RestoreWarning>, CacheError> {
+        let history = self.history(file_path)?;
+        if history.is_empty() {
+            return Err(CacheError::FileNotFound(file_path.to_path_buf()));
+        }
+        let Some(version) = history.get(version.wrapping_sub(1)) else {
+            return Err(CacheError::VersionNotFound(
+                file_path.to_path_buf(),
+                version,
+            ));
+        };
+        self.restore_from(file_path, version.id)
+    }
+
+    /// Restore a file to how it was at `time`, in nanoseconds since the epoch.
+    ///
+    /// This is the oldest version taken since then, which is removed along with
+    /// every later one.
+    pub fn restore_before(
+        &mut self,
+        file_path: &Path,
+        time: i64,
+    ) -> Result<Vec<RestoreWarning>, CacheError> {
+        let first: Option<i64> = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT MIN(files.id) FROM {} WHERE path = ?1 AND {} >= ?2",
+                    WITH_SESSIONS, TAKEN
+                ),
+                params![file_path.as_os_str().as_bytes(), time],
+                |row| row.get(0),
+            )
+            .map_err(CacheError::Rusqlite)?;
+        match first {
+            Some(first) => self.restore_from(file_path, first),
+            None if self.has_backup(file_path)? => {
+                Err(CacheError::NoChangesSince(file_path.to_path_buf()))
+            }
+            None => Err(CacheError::FileNotFound(file_path.to_path_buf())),
+        }
+    }
+
+    /// Restore a file to how it was before `session` changed it, and remove
+    /// that session's backup of it.
+    ///
+    /// Backups taken by other sessions are kept. Restoring is refused if a
+    /// later session also backed the file up, as its changes would be lost.
+    pub fn restore_session(
+        &mut self,
+        session: i64,
+        file_path: &Path,
+    ) -> Result<Vec<RestoreWarning>, CacheError> {
+        let (id, kind): (i64, String) = self
+            .conn
+            .query_row(
+                "SELECT id, kind FROM files WHERE session = ?1 AND path = ?2",
+                params![session, file_path.as_os_str().as_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(CacheError::Rusqlite)?
+            .ok_or_else(|| CacheError::FileNotFound(file_path.to_path_buf()))?;
+
+        // Times alone neither undo nor get undone by anything that matters.
+        let later: Option<i64> = match Kind::parse(&kind) {
+            Kind::Times => None,
+            _ => self
+                .conn
+                .query_row(
+                    "SELECT session FROM files
+                    WHERE path = ?1 AND id > ?2 AND session != ?3 AND kind != ?4
+                    ORDER BY id DESC LIMIT 1",
+                    params![
+                        file_path.as_os_str().as_bytes(),
+                        id,
+                        session,
+                        Kind::Times.as_str()
+                    ],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(CacheError::Rusqlite)?,
+        };
+        if let Some(later) = later {
+            return Err(CacheError::ChangedLater(file_path.to_path_buf(), later));
+        }
+
+        self.restore_row(file_path, id, false)
+    }
+
+    /// Check that every stored version reconstructs to exactly the content it
+    /// was backed up from.
+    pub fn verify(&self) -> Result<Verification, CacheError> {
+        blob::verify(&self.conn)
+    }
+
+    /// Get every version of a file the cache holds, oldest first.
+    pub fn history(&self, file_path: &Path) -> Result<Vec<Version>, CacheError> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT files.id, session, {}, kind, blobs.size, files.pid FROM {}
+                LEFT JOIN blobs ON blobs.hash = files.blob
+                WHERE path = ?1 AND kind != ?2 ORDER BY files.id",
+                TAKEN, WITH_SESSIONS
+            ))
+            .map_err(CacheError::Rusqlite)?;
+
+        let rows = stmt
+            .query_map(
+                params![file_path.as_os_str().as_bytes(), Kind::Times.as_str()],
+                |row| {
+                    let kind: String = row.get(3)?;
+                    Ok(Version {
+                        number: 0,
+                        id: row.get(0)?,
+                        session: row.get(1)?,
+                        taken: row.get(2)?,
+                        kind: Kind::parse(&kind),
+                        size: row.get(4)?,
+                        pid: row.get(5)?,
+                    })
+                },
+            )
+            .map_err(CacheError::Rusqlite)?;
+
+        let mut history = rows
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(CacheError::Rusqlite)?;
+        for (i, version) in history.iter_mut().enumerate() {
+            version.number = i + 1;
+        }
+        Ok(history)
+    }
+
+    /// Reports whether the cache holds any backup of `file_path`.
+    fn has_backup(&self, file_path: &Path) -> Result<bool, CacheError> {
+        self.conn
+            .prepare("SELECT 1 FROM files WHERE path = ?")
+            .and_then(|mut stmt| stmt.exists(params![file_path.as_os_str().as_bytes()]))
+            .map_err(CacheError::Rusqlite)
+    }
+
+    /// Restores a file to how it was before the backup with id `first`, and
+    /// removes that backup and every later one.
+    ///
+    /// The oldest full backup from there on gives the file back, but an older
+    /// backup of a directory's times alone has its original times.
+    fn restore_from(
+        &mut self,
+        file_path: &Path,
+        first: i64,
+    ) -> Result<Vec<RestoreWarning>, CacheError> {
+        let oldest = |times: bool| {
+            self.conn
+                .query_row(
+                    "SELECT MIN(id) FROM files WHERE path = ?1 AND id >= ?2 AND (kind = ?3) = ?4",
+                    params![
+                        file_path.as_os_str().as_bytes(),
+                        first,
+                        Kind::Times.as_str(),
+                        times
+                    ],
+                    |row| row.get::<_, Option<i64>>(0),
+                )
+                .map_err(CacheError::Rusqlite)
+        };
+        let (full, times) = (oldest(false)?, oldest(true)?);
+
+        let warnings = match full {
+            Some(full) => self.restore_row(file_path, full, true)?,
+            None => Vec::new(),
+        };
+        match times {
+            Some(times) if full.is_none_or(|full| times < full) => {
+                self.restore_row(file_path, times, true)?;
+            }
+            _ if full.is_none() => return Err(CacheError::FileNotFound(file_path.to_path_buf())),
+            _ => {}
+        }
+        Ok(warnings)
+    }
+
+    /// Restores the backup with the given `id`, and removes it along with every
+    /// later version if `later` is set. See `restore`.
+    fn restore_row(
+        &mut self,
+        file_path: &Path,
+        id: i64,
+        later: bool,
+    ) -> Result<Vec<RestoreWarning>, CacheError> {
+        let tx = self.conn.transaction().map_err(CacheError::Rusqlite)?;
 
         let mut stmt = tx
-            .prepare("SELECT content, permissions FROM files WHERE path = ?")
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .prepare(
+                "SELECT blob, permissions, kind, uid, gid, atime, mtime, xattrs,
+                dev, ino, nlink, links
+                FROM files WHERE id = ?",
+            )
+            .map_err(CacheError::Rusqlite)?;
 
-        let mut rows = stmt
-            .query(params![file_path.to_string_lossy()])
-            .map_err(|e| CacheError::Rusqlite(e))?;
+        let mut rows = stmt.query(params![id]).map_err(CacheError::Rusqlite)?;
 
         let result = if let Some(row) = rows.next()? {
-            let content: Vec<u8> = row.get(0).map_err(|e| CacheError::Rusqlite(e))?;
-            let permissions: u32 = row.get(1).map_err(|e| CacheError::Rusqlite(e))?;
+            let content = row
+                .get::<_, Option<Hash>>(0)
+                .map_err(CacheError::Rusqlite)?
+                .map(|hash| blob::load(&tx, &hash))
+                .transpose()?;
+            let kind: String = row.get(2).map_err(CacheError::Rusqlite)?;
+            let links = Links {
+                dev: row.get(8).map_err(CacheError::Rusqlite)?,
+                ino: row.get(9).map_err(CacheError::Rusqlite)?,
+                nlink: row.get(10).map_err(CacheError::Rusqlite)?,
+                names: row
+                    .get::<_, Option<Vec<u8>>>(11)
+                    .map_err(CacheError::Rusqlite)?
+                    .map(|names| decode_names(&names))
+                    .unwrap_or_default(),
+            };
+            let mut warnings = Vec::new();
+            let attributes = Attributes {
+                permissions: row.get(1).map_err(CacheError::Rusqlite)?,
+                uid: row.get(3).map_err(CacheError::Rusqlite)?,
+                gid: row.get(4).map_err(CacheError::Rusqlite)?,
+                atime: row.get(5).map_err(CacheError::Rusqlite)?,
+                mtime: row.get(6).map_err(CacheError::Rusqlite)?,
+                xattrs: row.get(7).map_err(CacheError::Rusqlite)?,
+            };
 
-            let mut file = File::create(file_path).map_err(|e| CacheError::Io(e))?;
-            file.write_all(&content).map_err(|e| CacheError::Io(e))?;
+            let parent = file_path.parent().filter(|parent| parent.is_dir());
+            let parent_attributes = parent
+                .and_then(|parent| fs::metadata(parent).ok())
+                .map(|metadata| Attributes::of(&metadata));
 
-            let metadata = fs::metadata(file_path).map_err(|e| CacheError::Io(e))?;
-            let mut perms = metadata.permissions();
-            perms.set_mode(permissions);
-            fs::set_permissions(file_path, perms).map_err(|e| CacheError::Io(e))?;
+            let kind = Kind::parse(&kind);
+            // Whatever of another type took the path's place goes first. Writing
+            // through a symlink would change its target instead, and a symlink
+            // can only be created where nothing exists. A directory in the way
+            // must be empty, or the revert fails rather than lose its content.
+            let current = fs::symlink_metadata(file_path).ok();
+            let in_the_way = current.filter(|current| match kind {
+                Kind::Directory => !current.is_dir(),
+                Kind::File => !current.is_file(),
+                Kind::Symlink => true,
+                Kind::Absent | Kind::Times => false,
+            });
+            match in_the_way {
+                Some(current) if current.is_dir() => {
+                    fs::remove_dir(file_path).map_err(CacheError::Io)?
+                }
+                Some(_) => fs::remove_file(file_path).map_err(CacheError::Io)?,
+                None => {}
+            }
+
+            match kind {
+                Kind::Directory => {
+                    fs::create_dir_all(file_path).map_err(CacheError::Io)?;
+                    warnings.extend(apply_attributes(&attributes, file_path)?);
+                }
+                Kind::File => {
+                    if let Some(parent) = file_path.parent() {
+                        fs::create_dir_all(parent).map_err(CacheError::Io)?;
+                    }
+                    // A file split off from its hard links joins one of them
+                    // again, and the others are pulled back to it afterwards.
+                    let content = content.unwrap_or_default();
+                    restore_links(&tx, file_path, &content, &links)?;
+                    let mut file = File::create(file_path).map_err(CacheError::Io)?;
+                    file.write_all(&content).map_err(CacheError::Io)?;
+                    warnings.extend(apply_attributes(&attributes, file_path)?);
+                    warnings.extend(pull_links(&tx, file_path, &links)?);
+                }
+                Kind::Symlink => {
+                    if let Some(parent) = file_path.parent() {
+                        fs::create_dir_all(parent).map_err(CacheError::Io)?;
+                    }
+                    let target = OsString::from_vec(content.unwrap_or_default());
+                    unix_fs::symlink(target, file_path).map_err(CacheError::Io)?;
+                    warnings.extend(apply_attributes(&attributes, file_path)?);
+                }
+                // Only the owner may set a directory's times, so this is best effort.
+                Kind::Times => {
+                    if file_path.is_dir() {
+                        let _ = attributes.apply_times(file_path);
+                    }
+                }
+                // Created directories are only removed if empty.
+                Kind::Absent => match fs::symlink_metadata(file_path) {
+                    Ok(metadata) if metadata.is_dir() => {
+                        fs::remove_dir(file_path).map_err(CacheError::Io)?
+                    }
+                    Ok(_) => fs::remove_file(file_path).map_err(CacheError::Io)?,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(CacheError::Io(err)),
+                },
+            }
+
+            // Restoring touched the parent; putting its times back leaves a
+            // restored tree with its original modification times. Only the
+            // owner may set them, so this is best effort.
+            if let (Some(parent), Some(parent_attributes)) = (parent, parent_attributes) {
+                let _ = parent_attributes.apply_times(parent);
+            }
 
             tx.execute(
-                "DELETE FROM files WHERE path = ?",
-                params![file_path.to_string_lossy()],
+                "DELETE FROM files WHERE path = ?1 AND (id = ?2 OR (?3 AND id > ?2))",
+                params![file_path.as_os_str().as_bytes(), id, later],
             )
-            .map_err(|e| CacheError::Rusqlite(e))?;
+            .map_err(CacheError::Rusqlite)?;
 
-            Ok(())
+            Ok(warnings)
         } else {
             Err(CacheError::FileNotFound(file_path.to_path_buf()))
         };
 
         drop(rows);
         drop(stmt);
-        tx.commit().map_err(|e| CacheError::Rusqlite(e))?;
+        tx.commit().map_err(CacheError::Rusqlite)?;
 
         result
     }
 }
+
+/// Moves the destination of a rename back to its source, or swaps the two back.
+///
+/// Nothing is moved if the program removed the destination since; the
+/// source is then rebuilt from its backup instead.
+fn move_back(rename: &Rename) -> Result<(), CacheError> {
+    let exists = |path: &Path| fs::symlink_metadata(path).is_ok();
+    if rename.exchange {
+        if exists(&rename.from) && exists(&rename.to) {
+            renameat2(
+                None,
+                &rename.to,
+                None,
+                &rename.from,
+                RenameFlags::RENAME_EXCHANGE,
+            )
+            .map_err(|e| CacheError::Io(e.into()))?;
+        }
+    } else if exists(&rename.to) {
+        if let Some(parent) = rename.from.parent() {
+            fs::create_dir_all(parent).map_err(CacheError::Io)?;
+        }
+        fs::rename(&rename.to, &rename.from).map_err(CacheError::Io)?;
+    }
+    Ok(())
+}
+
+/// Applies the attributes of a restored path, warning rather than failing when
+/// the caller lacks the privileges to.
+fn apply_attributes(
+    attributes: &Attributes,
+    path: &Path,
+) -> Result<Option<RestoreWarning>, CacheError> {
+    match attributes.apply(path) {
+        Ok(()) => Ok(None),
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+            Ok(Some(RestoreWarning::AttributesLost {
+                path: path.to_path_buf(),
+                error,
+            }))
+        }
+        Err(error) => Err(CacheError::Io(error)),
+    }
+}
+
+/// Moves the backups of paths inside a renamed tree that were taken after the
+/// rename, naming them by where they are once the rename is undone.
+///
+/// Where a session already has a backup under the new name, the one taken
+/// first is kept, since it is the closer to how the path was before.
+fn remap_backups(tx: &Transaction, rename: &Rename, taken: i64) -> Result<(), CacheError> {
+    // Joining an empty path would add a trailing slash.
+    let rebase = |base: &Path, rest: &Path| match rest.as_os_str().is_empty() {
+        true => base.to_path_buf(),
+        false => base.join(rest),
+    };
+    let moved = |path: &Path| match path.strip_prefix(&rename.to) {
+        Ok(rest) => Some(rebase(&rename.from, rest)),
+        Err(_) if rename.exchange => path
+            .strip_prefix(&rename.from)
+            .ok()
+            .map(|rest| rebase(&rename.to, rest)),
+        Err(_) => None,
+    };
+
+    let backups = {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT files.id, files.session, path, {} FROM {} WHERE {} > ?",
+                TAKEN, WITH_SESSIONS, TAKEN
+            ))
+            .map_err(CacheError::Rusqlite)?;
+        let rows = stmt
+            .query_map(params![taken], |row| {
+                let path: Vec<u8> = row.get(2)?;
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    PathBuf::from(OsString::from_vec(path)),
+                    row.get::<_, i64>(3)?,
+                ))
+            })
+            .map_err(CacheError::Rusqlite)?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(CacheError::Rusqlite)?
+            .into_iter()
+            .filter_map(|(id, session, path, taken)| Some((id, session, moved(&path)?, taken)))
+            .collect::<Vec<_>>()
+    };
+
+    // Park the moving backups under relative names, which no backed-up path
+    // has, so that swapping two trees never clashes with itself.
+    for (id, ..) in &backups {
+        tx.execute(
+            "UPDATE files SET path = CAST('moving/' || id AS BLOB) WHERE id = ?",
+            params![id],
+        )
+        .map_err(CacheError::Rusqlite)?;
+    }
+
+    for (id, session, path, taken) in backups {
+        let existing: Option<(i64, i64)> = tx
+            .query_row(
+                &format!(
+                    "SELECT files.id, {} FROM {} WHERE files.session = ?1 AND path = ?2",
+                    TAKEN, WITH_SESSIONS
+                ),
+                params![session, path.as_os_str().as_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(CacheError::Rusqlite)?;
+
+        match existing {
+            Some((_, existing_taken)) if existing_taken <= taken => {
+                tx.execute("DELETE FROM files WHERE id = ?", params![id])
+                    .map_err(CacheError::Rusqlite)?;
+            }
+            existing => {
+                if let Some((existing, _)) = existing {
+                    tx.execute("DELETE FROM files WHERE id = ?", params![existing])
+                        .map_err(CacheError::Rusqlite)?;
+                }
+                tx.execute(
+                    "UPDATE files SET path = ?1 WHERE id = ?2",
+                    params![path.as_os_str().as_bytes(), id],
+                )
+                .map_err(CacheError::Rusqlite)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How a backed-up file was linked to the rest of the filesystem.
+struct Links {
+    dev: Option<i64>,
+    ino: Option<i64>,
+    nlink: Option<i64>,
+    /// Other names the inode had in the same directory.
+    names: Vec<PathBuf>,
+}
+
+impl Links {
+    /// Whether the file had hard links other than itself.
+    fn shared(&self) -> bool {
+        self.nlink.is_some_and(|nlink| nlink > 1)
+    }
+
+    /// Whether `metadata` describes the inode that was backed up.
+    fn is_inode(&self, metadata: &fs::Metadata) -> bool {
+        self.dev == Some(metadata.dev() as i64) && self.ino == Some(metadata.ino() as i64)
+    }
+
+    /// Whether the file at `path` is the backed-up inode, as far as can be told.
+    fn is_file(&self, path: &Path) -> bool {
+        fs::symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.is_file() && self.is_inode(&metadata))
+    }
+}
+
+/// Returns the paths hard linked with `file_path`, other than itself.
+///
+/// These are the recorded names, plus any backed-up path that was the same
+/// inode. Each comes with whether it still has a backup waiting to be restored.
+fn link_group(
+    conn: &Connection,
+    file_path: &Path,
+    links: &Links,
+) -> Result<Vec<(PathBuf, bool)>, CacheError> {
+    let mut stmt = conn
+        .prepare("SELECT path FROM files WHERE dev = ?1 AND ino = ?2 AND path != ?3")
+        .map_err(CacheError::Rusqlite)?;
+    let pending = stmt
+        .query_map(
+            params![links.dev, links.ino, file_path.as_os_str().as_bytes()],
+            |row| {
+                let path: Vec<u8> = row.get(0)?;
+                Ok(PathBuf::from(OsString::from_vec(path)))
+            },
+        )
+        .map_err(CacheError::Rusqlite)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(CacheError::Rusqlite)?;
+
+    let mut group: Vec<_> = links
+        .names
+        .iter()
+        .filter(|name| !pending.contains(name))
+        .map(|name| (name.clone(), false))
+        .collect();
+    group.extend(pending.into_iter().map(|path| (path, true)));
+    Ok(group)
+}
+
+/// Makes `file_path` a hard link to one of its former names again before its
+/// content is restored, if it has been split off or deleted.
+///
+/// A name qualifies if it still holds the backed-up inode, or has already been
+/// restored to the same content.
+fn restore_links(
+    conn: &Connection,
+    file_path: &Path,
+    content: &[u8],
+    links: &Links,
+) -> Result<(), CacheError> {
+    if !links.shared() || links.is_file(file_path) {
+        return Ok(());
+    }
+
+    let partner = link_group(conn, file_path, links)?
+        .into_iter()
+        .filter(|(_, pending)| !pending)
+        .map(|(name, _)| name)
+        .find(|name| {
+            links.is_file(name)
+                || (name.is_file() && fs::read(name).is_ok_and(|other| other == content))
+        });
+
+    if let Some(partner) = partner {
+        if fs::symlink_metadata(file_path).is_ok() {
+            fs::remove_file(file_path).map_err(CacheError::Io)?;
+        }
+        fs::hard_link(partner, file_path).map_err(CacheError::Io)?;
+    }
+    Ok(())
+}
+
+/// Links the former names of `file_path` that are still waiting to be restored
+/// back to it, once its content has been restored.
+///
+/// Warns if the file had other links but is now on its own.
+fn pull_links(
+    conn: &Connection,
+    file_path: &Path,
+    links: &Links,
+) -> Result<Vec<RestoreWarning>, CacheError> {
+    if !links.shared() {
+        return Ok(Vec::new());
+    }
+
+    let restored = fs::symlink_metadata(file_path).map_err(CacheError::Io)?;
+    for (name, _) in link_group(conn, file_path, links)?
+        .into_iter()
+        .filter(|(_, pending)| *pending)
+    {
+        match fs::symlink_metadata(&name) {
+            Ok(current)
+                if current.is_dir()
+                    || (current.dev() == restored.dev() && current.ino() == restored.ino()) =>
+            {
+                continue
+            }
+            Ok(_) => fs::remove_file(&name).map_err(CacheError::Io)?,
+            Err(_) => {}
+        }
+        fs::hard_link(file_path, &name).map_err(CacheError::Io)?;
+    }
+
+    let restored = fs::symlink_metadata(file_path).map_err(CacheError::Io)?;
+    if restored.nlink() > 1 {
+        return Ok(Vec::new());
+    }
+    Ok(vec![RestoreWarning::LinksLost {
+        path: file_path.to_path_buf(),
+        links: links.nlink.unwrap_or_default() as u64,
+    }])
+}
+
+/// Returns the other names the inode of `file_path` has in its directory.
+///
+/// Links elsewhere would take a search of the whole filesystem to find.
+fn link_names(file_path: &Path, metadata: &fs::Metadata) -> Vec<PathBuf> {
+    if !metadata.is_file() || metadata.nlink() < 2 {
+        return Vec::new();
+    }
+
+    file_path
+        .parent()
+        .and_then(|parent| fs::read_dir(parent).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path != file_path
+                && fs::symlink_metadata(path).is_ok_and(|other| {
+                    other.dev() == metadata.dev() && other.ino() == metadata.ino()
+                })
+        })
+        .collect()
+}
+
+/// Joins paths with null bytes, which cannot occur in them.
+fn encode_names(names: &[PathBuf]) -> Option<Vec<u8>> {
+    if names.is_empty() {
+        return None;
+    }
+    Some(
+        names
+            .iter()
+            .map(|name| name.as_os_str().as_bytes())
+            .collect::<Vec<_>>()
+            .join(&0),
+    )
+}
+
+/// Splits paths joined by `encode_names`.
+fn decode_names(bytes: &[u8]) -> Vec<PathBuf> {
+    bytes
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(OsString::from_vec(name.to_vec())))
+        .collect()
+}
+
+/// The files table joined with the sessions that took them.
+const WITH_SESSIONS: &str = "files JOIN sessions ON sessions.id = files.session";
+
+/// When a backup was taken. Older backups fall back to the start of their session.
+const TAKEN: &str = "COALESCE(files.taken, sessions.started)";
+
+/// Returns the statement creating the files table, named by `table`.
+///
+/// Every session has its own backup of each path it changed.
+fn files_schema(table: &str) -> String {
+    format!(
+        "CREATE TABLE {} (
+            id INTEGER PRIMARY KEY,
+            session INTEGER NOT NULL REFERENCES sessions (id),
+            path BLOB NOT NULL,
+            blob BLOB REFERENCES blobs (hash),
+            permissions INTEGER,
+            kind TEXT NOT NULL DEFAULT 'file',
+            uid INTEGER,
+            gid INTEGER,
+            atime INTEGER,
+            mtime INTEGER,
+            xattrs BLOB,
+            dev INTEGER,
+            ino INTEGER,
+            nlink INTEGER,
+            links BLOB,
+            taken INTEGER,
+            pid INTEGER,
+            UNIQUE (session, path)
+        )",
+        table
+    )
+}
+
+/// Rebuilds a files table with one backup per path into one keyed by session.
+///
+/// The existing backups are filed under a session with an empty command line.
+fn migrate_to_sessions(conn: &Connection) -> Result<(), CacheError> {
+    let columns = "path, blob, permissions, kind, uid, gid, atime, mtime, xattrs, \
+        dev, ino, nlink, links, taken, pid";
+    conn.execute_batch(&format!(
+        "BEGIN;
+        INSERT INTO sessions (command, cwd, started) VALUES (X'', X'', {now});
+        {schema};
+        INSERT INTO files_new (id, session, {columns})
+            SELECT id, last_insert_rowid(), {columns} FROM files;
+        DELETE FROM sessions WHERE NOT EXISTS (SELECT 1 FROM files_new);
+        DROP TABLE files;
+        ALTER TABLE files_new RENAME TO files;
+        COMMIT;",
+        now = now(),
+        schema = files_schema("files_new"),
+        columns = columns,
+    ))
+    .map_err(CacheError::Rusqlite)
+}
+
+/// Encodes a command line as null-terminated arguments.
+fn encode_args(args: &[OsString]) -> Vec<u8> {
+    args.iter()
+        .flat_map(|arg| arg.as_bytes().iter().copied().chain([0]))
+        .collect()
+}
+
+/// Decodes a command line encoded by `encode_args`.
+fn decode_args(bytes: &[u8]) -> Vec<OsString> {
+    let Some(bytes) = bytes.strip_suffix(&[0]) else {
+        return Vec::new();
+    };
+    bytes
+        .split(|&byte| byte == 0)
+        .map(|arg| OsStr::from_bytes(arg).to_os_string())
+        .collect()
+}
+
+/// Reports whether `table` has a column called `name`.
+fn has_column(conn: &Connection, table: &str, name: &str) -> Result<bool, CacheError> {
+    conn.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+        .and_then(|mut stmt| stmt.exists(params![table, name]))
+        .map_err(CacheError::Rusqlite)
+}
+
+/// Adds a column to `table` in a database created before it existed.
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+    declaration: &str,
+) -> Result<(), CacheError> {
+    if !has_column(conn, table, name)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, declaration),
+            params![],
+        )
+        .map_err(CacheError::Rusqlite)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Xattrs;
+    use nix::libc;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory of its own for each test, removed when the test ends.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let dir = env::temp_dir().join(format!(
+                "undo-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            // Canonical, like the paths the tracer backs up.
+            Scratch(fs::canonicalize(dir).unwrap())
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        /// Opens a cache kept inside the scratch directory, with a session started.
+        fn cache(&self) -> (Cache, i64) {
+            let cache = Cache::open(&self.0.join("cache.db")).unwrap();
+            let session = cache.start_session(&[], &self.0, 1).unwrap();
+            (cache, session)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// Restores every path the way `revert all` does.
+    fn restore_all(cache: &mut Cache) -> Vec<RestoreWarning> {
+        let mut warnings = Vec::new();
+        for path in cache.restore_order(None, None).unwrap() {
+            warnings.extend(cache.restore(&path).unwrap());
+        }
+        warnings
+    }
+
+    #[test]
+    fn deleted_file_is_recreated_with_its_mode() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let file = scratch.path("file");
+        fs::write(&file, "content").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+
+        cache.backup(session, 1, &file).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        assert!(cache.restore(&file).unwrap().is_empty());
+        assert_eq!(fs::read(&file).unwrap(), b"content");
+        assert_eq!(mode(&file), 0o640);
+        assert!(matches!(
+            cache.restore(&file),
+            Err(CacheError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn changed_file_gets_its_content_and_owner_back() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let file = scratch.path("file");
+        fs::write(&file, "before").unwrap();
+        let owner = fs::metadata(&file).unwrap();
+
+        cache.backup(session, 1, &file).unwrap();
+        fs::write(&file, "after, and longer").unwrap();
+        // Only root may give a file away.
+        if nix::unistd::geteuid().is_root() {
+            std::os::unix::fs::chown(&file, Some(1), Some(1)).unwrap();
+        }
+
+        cache.restore(&file).unwrap();
+        let restored = fs::metadata(&file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"before");
+        assert_eq!((restored.uid(), restored.gid()), (owner.uid(), owner.gid()));
+    }
+
+    #[test]
+    fn deleted_tree_is_recreated_with_missing_parents() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let dir = scratch.path("dir");
+        let file = scratch.path("dir/sub/file");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "deep").unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o750)).unwrap();
+
+        // The file first, as `rm -r` backs it up before its directories.
+        cache.backup(session, 1, &file).unwrap();
+        cache.backup(session, 1, &dir.join("sub")).unwrap();
+        cache.backup(session, 1, &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Restoring the file alone brings back the directories it needs.
+        cache.restore(&file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"deep");
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert_eq!(mode(&dir), 0o750);
+    }
+
+    #[test]
+    fn created_file_is_removed() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let file = scratch.path("new");
+
+        cache.backup(session, 1, &file).unwrap();
+        fs::write(&file, "created").unwrap();
+
+        cache.restore(&file).unwrap();
+        assert!(fs::symlink_metadata(&file).is_err());
+    }
+
+    #[test]
+    fn created_directory_is_removed_only_if_empty() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let empty = scratch.path("empty");
+        let full = scratch.path("full");
+
+        cache.backup(session, 1, &empty).unwrap();
+        cache.backup(session, 1, &full).unwrap();
+        fs::create_dir(&empty).unwrap();
+        fs::create_dir(&full).unwrap();
+        fs::write(full.join("untracked"), "keep me").unwrap();
+
+        cache.restore(&empty).unwrap();
+        assert!(!empty.exists());
+
+        match cache.restore(&full) {
+            Err(CacheError::Io(err)) => assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY)),
+            result => panic!("expected ENOTEMPTY, got {:?}", result),
+        }
+        assert!(full.join("untracked").exists());
+        // The tombstone stays, so the revert can be retried once the directory is emptied.
+        fs::remove_file(full.join("untracked")).unwrap();
+        cache.restore(&full).unwrap();
+        assert!(!full.exists());
+    }
+
+    #[test]
+    fn restore_order_removes_created_trees_bottom_up_and_recreates_deleted_ones_top_down() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let old = scratch.path("old");
+        let new = scratch.path("new");
+        fs::create_dir_all(old.join("a")).unwrap();
+        fs::write(old.join("a/file"), "old").unwrap();
+
+        // As `rm -r old; mkdir -p new/a; touch new/a/file` backs them up.
+        for path in ["old/a/file", "old/a", "old", "new", "new/a", "new/a/file"] {
+            cache.backup(session, 1, &scratch.path(path)).unwrap();
+        }
+        fs::remove_dir_all(&old).unwrap();
+        fs::create_dir_all(new.join("a")).unwrap();
+        fs::write(new.join("a/file"), "new").unwrap();
+
+        let order = cache.restore_order(None, None).unwrap();
+        let expected: Vec<_> = ["new/a/file", "new/a", "new", "old", "old/a", "old/a/file"]
+            .iter()
+            .map(|path| scratch.path(path))
+            .collect();
+        assert_eq!(order, expected);
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert!(!new.exists());
+        assert_eq!(fs::read(old.join("a/file")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn directory_replaced_by_a_file_is_recreated() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let build = scratch.path("build");
+        fs::create_dir(&build).unwrap();
+        fs::write(build.join("out"), "artifact").unwrap();
+
+        // rm -rf build; echo file > build
+        cache.backup(session, 1, &build.join("out")).unwrap();
+        cache.backup(session, 1, &build).unwrap();
+        fs::remove_dir_all(&build).unwrap();
+        fs::write(&build, "file").unwrap();
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert!(build.is_dir());
+        assert_eq!(fs::read(build.join("out")).unwrap(), b"artifact");
+    }
+
+    #[test]
+    fn file_replaced_by_a_directory_is_recreated() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let file = scratch.path("f");
+        fs::write(&file, "file").unwrap();
+
+        // rm f; mkdir f
+        cache.backup(session, 1, &file).unwrap();
+        fs::remove_file(&file).unwrap();
+        fs::create_dir(&file).unwrap();
+
+        cache.restore(&file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"file");
+    }
+
+    #[test]
+    fn file_replaced_by_a_non_empty_directory_is_reported() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let file = scratch.path("f");
+        fs::write(&file, "file").unwrap();
+
+        cache.backup(session, 1, &file).unwrap();
+        fs::remove_file(&file).unwrap();
+        fs::create_dir(&file).unwrap();
+        fs::write(file.join("inside"), "keep me").unwrap();
+
+        assert!(matches!(cache.restore(&file), Err(CacheError::Io(_))));
+        assert!(file.join("inside").exists());
+    }
+
+    #[test]
+    fn rewritten_symlink_is_restored_as_a_link() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let target = scratch.path("target");
+        let other = scratch.path("other");
+        let link = scratch.path("link");
+        fs::write(&target, "target").unwrap();
+        fs::write(&other, "other").unwrap();
+        unix_fs::symlink("target", &link).unwrap();
+
+        cache.backup(session, 1, &link).unwrap();
+        fs::remove_file(&link).unwrap();
+        unix_fs::symlink(&other, &link).unwrap();
+
+        cache.restore(&link).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("target"));
+        assert_eq!(fs::read(&target).unwrap(), b"target");
+        assert_eq!(fs::read(&other).unwrap(), b"other");
+
+        // A link replaced by a regular file comes back as a link too.
+        cache.backup(session, 1, &link).unwrap();
+        fs::remove_file(&link).unwrap();
+        fs::write(&link, "plain file").unwrap();
+
+        cache.restore(&link).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"target");
+    }
+
+    #[test]
+    fn split_hard_link_is_rejoined() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let a = scratch.path("a");
+        let b = scratch.path("b");
+        fs::write(&a, "shared").unwrap();
+        fs::hard_link(&a, &b).unwrap();
+
+        // Split it off, as editors that save by writing a new file do.
+        cache.backup(session, 1, &b).unwrap();
+        fs::remove_file(&b).unwrap();
+        fs::write(&b, "edited").unwrap();
+
+        assert!(cache.restore(&b).unwrap().is_empty());
+        let (a, b) = (fs::metadata(&a).unwrap(), fs::metadata(&b).unwrap());
+        assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+        assert_eq!(b.nlink(), 2);
+    }
+
+    #[test]
+    fn hard_link_without_partners_is_reported() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let a = scratch.path("a");
+        let b = scratch.path("b");
+        fs::write(&a, "shared").unwrap();
+        fs::hard_link(&a, &b).unwrap();
+
+        cache.backup(session, 1, &a).unwrap();
+        fs::remove_file(&a).unwrap();
+        // The other link goes too, without being tracked.
+        fs::remove_file(&b).unwrap();
+
+        let warnings = cache.restore(&a).unwrap();
+        assert!(matches!(
+            warnings.as_slice(),
+            [RestoreWarning::LinksLost { links: 2, .. }]
+        ));
+        assert_eq!(fs::read(&a).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn session_revert_is_refused_while_a_later_session_changed_the_file() {
+        let scratch = Scratch::new();
+        let (mut cache, first) = scratch.cache();
+        let second = cache.start_session(&[], &scratch.0, 2).unwrap();
+        let file = scratch.path("file");
+        fs::write(&file, "original").unwrap();
+
+        cache.backup(first, 1, &file).unwrap();
+        fs::write(&file, "first").unwrap();
+        cache.backup(second, 2, &file).unwrap();
+        fs::write(&file, "second").unwrap();
+
+        assert!(matches!(
+            cache.restore_session(first, &file),
+            Err(CacheError::ChangedLater(_, session)) if session == second
+        ));
+        assert_eq!(fs::read(&file).unwrap(), b"second");
+
+        cache.restore_session(second, &file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"first");
+        cache.restore_session(first, &file).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"original");
+    }
+
+    #[test]
+    fn directory_times_stay_out_of_the_way() {
+        let scratch = Scratch::new();
+        let (mut cache, first) = scratch.cache();
+        let second = cache.start_session(&[], &scratch.0, 2).unwrap();
+        let dir = scratch.path("dir");
+        fs::create_dir(&dir).unwrap();
+        let mut before_second = None;
+
+        // touch dir/a; touch dir/b, as two runs.
+        for (session, name) in [(first, "a"), (second, "b")] {
+            before_second = Some(Attributes::of(&fs::metadata(&dir).unwrap()));
+            cache.backup(session, 1, &dir.join(name)).unwrap();
+            assert!(cache.backup_times(session, 1, &dir).unwrap());
+            assert!(!cache.backup_times(session, 1, &dir).unwrap());
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        assert_eq!(cache.list(first).unwrap(), [dir.join("a")]);
+        assert!(cache.history(&dir).unwrap().is_empty());
+
+        cache.restore_session(first, &dir.join("a")).unwrap();
+        cache.restore_session(first, &dir).unwrap();
+        assert!(!dir.join("a").exists());
+        assert_eq!(
+            cache
+                .sessions()
+                .unwrap()
+                .iter()
+                .map(|session| session.id)
+                .collect::<Vec<_>>(),
+            [second]
+        );
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert!(!dir.join("b").exists());
+        let restored = Attributes::of(&fs::metadata(&dir).unwrap());
+        assert_eq!(restored.mtime, before_second.unwrap().mtime);
+    }
+
+    #[test]
+    fn full_backup_keeps_the_times_saved_earlier_in_the_session() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let dir = scratch.path("dir");
+        fs::create_dir(&dir).unwrap();
+        let original = Attributes::of(&fs::metadata(&dir).unwrap());
+
+        // touch dir/a; chmod 700 dir
+        cache.backup(session, 1, &dir.join("a")).unwrap();
+        cache.backup_times(session, 1, &dir).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(dir.join("a"), "a").unwrap();
+        cache.backup(session, 1, &dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+
+        assert_eq!(cache.history(&dir).unwrap().len(), 1);
+        assert!(restore_all(&mut cache).is_empty());
+        let restored = Attributes::of(&fs::metadata(&dir).unwrap());
+        assert_eq!(restored.permissions, original.permissions);
+        assert_eq!(restored.mtime, original.mtime);
+    }
+
+    /// Backs `file` up in three sessions, leaving it as "third"; version n
+    /// holds what the file was before session n.
+    fn three_versions(scratch: &Scratch) -> (Cache, PathBuf) {
+        let (cache, first) = scratch.cache();
+        let file = scratch.path("file");
+        fs::write(&file, "original").unwrap();
+        cache.backup(first, 1, &file).unwrap();
+        for (pid, content) in [(2, "second"), (3, "third")] {
+            fs::write(&file, content).unwrap();
+            let session = cache.start_session(&[], &scratch.0, pid).unwrap();
+            cache.backup(session, pid, &file).unwrap();
+        }
+        fs::write(&file, "fourth").unwrap();
+        (cache, file)
+    }
+
+    #[test]
+    fn restoring_a_version_drops_it_and_later_ones() {
+        let scratch = Scratch::new();
+        let (mut cache, file) = three_versions(&scratch);
+
+        assert!(matches!(
+            cache.restore_version(&file, 4),
+            Err(CacheError::VersionNotFound(_, 4))
+        ));
+        assert!(matches!(
+            cache.restore_version(&file, 0),
+            Err(CacheError::VersionNotFound(_, 0))
+        ));
+        assert!(matches!(
+            cache.restore_version(&scratch.path("other"), 1),
+            Err(CacheError::FileNotFound(_))
+        ));
+
+        cache.restore_version(&file, 2).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"second");
+        assert_eq!(cache.history(&file).unwrap().len(), 1);
+        cache.restore_version(&file, 1).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"original");
+        assert!(cache.history(&file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn restoring_before_a_time_uses_the_oldest_version_since() {
+        let scratch = Scratch::new();
+        let (mut cache, file) = three_versions(&scratch);
+        let history = cache.history(&file).unwrap();
+
+        assert!(matches!(
+            cache.restore_before(&file, history[2].taken + 1),
+            Err(CacheError::NoChangesSince(_))
+        ));
+        assert!(matches!(
+            cache.restore_before(&scratch.path("other"), 0),
+            Err(CacheError::FileNotFound(_))
+        ));
+
+        // Anything after the first backup up to the second picks the second.
+        cache.restore_before(&file, history[0].taken + 1).unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"second");
+        assert_eq!(cache.history(&file).unwrap().len(), 1);
+    }
+
+    /// Renames `from` to `to` the way `mv` does under the tracer.
+    fn rename(cache: &Cache, session: i64, from: &Path, to: &Path) {
+        cache.backup(session, 1, from).unwrap();
+        cache.backup(session, 1, to).unwrap();
+        fs::rename(from, to).unwrap();
+        let rename = Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            exchange: false,
+        };
+        cache.record_rename(session, &rename).unwrap();
+    }
+
+    #[test]
+    fn renamed_file_is_moved_back() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let (from, to) = (scratch.path("from"), scratch.path("to"));
+        fs::write(&from, "original").unwrap();
+
+        rename(&cache, session, &from, &to);
+        fs::write(&to, "edited").unwrap();
+
+        assert_eq!(cache.undo_renames(None, None, None).unwrap().len(), 1);
+        assert!(cache.undo_renames(None, None, None).unwrap().is_empty());
+        assert!(!to.exists());
+        assert!(restore_all(&mut cache).is_empty());
+        assert_eq!(fs::read(&from).unwrap(), b"original");
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn backups_taken_inside_a_renamed_directory_move_back_with_it() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let (from, to) = (scratch.path("from"), scratch.path("to"));
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("file"), "original").unwrap();
+
+        // mv from to; echo edited > to/file; touch to/new
+        rename(&cache, session, &from, &to);
+        cache.backup(session, 1, &to.join("file")).unwrap();
+        fs::write(to.join("file"), "edited").unwrap();
+        cache.backup(session, 1, &to.join("new")).unwrap();
+        fs::write(to.join("new"), "new").unwrap();
+
+        cache.undo_renames(None, None, None).unwrap();
+        assert_eq!(fs::read(from.join("file")).unwrap(), b"edited");
+        assert!(cache.history(&to.join("file")).unwrap().is_empty());
+        assert_eq!(cache.history(&from.join("file")).unwrap().len(), 1);
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert_eq!(fs::read(from.join("file")).unwrap(), b"original");
+        assert!(!from.join("new").exists());
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn exchanged_directories_swap_back_with_their_backups() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let (a, b) = (scratch.path("a"), scratch.path("b"));
+        for dir in [&a, &b] {
+            fs::create_dir(dir).unwrap();
+            fs::write(dir.join("file"), dir.file_name().unwrap().as_bytes()).unwrap();
+        }
+
+        // renameat2(a, b, RENAME_EXCHANGE); echo edited > a/file
+        cache.backup(session, 1, &a).unwrap();
+        cache.backup(session, 1, &b).unwrap();
+        renameat2(None, &a, None, &b, RenameFlags::RENAME_EXCHANGE).unwrap();
+        let rename = Rename {
+            from: a.clone(),
+            to: b.clone(),
+            exchange: true,
+        };
+        cache.record_rename(session, &rename).unwrap();
+        cache.backup(session, 1, &a.join("file")).unwrap();
+        fs::write(a.join("file"), "edited").unwrap();
+
+        cache.undo_renames(None, None, None).unwrap();
+        assert_eq!(fs::read(a.join("file")).unwrap(), b"a");
+        assert_eq!(fs::read(b.join("file")).unwrap(), b"edited");
+        assert!(cache.history(&a.join("file")).unwrap().is_empty());
+
+        assert!(restore_all(&mut cache).is_empty());
+        assert_eq!(fs::read(a.join("file")).unwrap(), b"a");
+        assert_eq!(fs::read(b.join("file")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn symlink_is_restored_even_if_its_attributes_cannot_be() {
+        let scratch = Scratch::new();
+        let (mut cache, session) = scratch.cache();
+        let link = scratch.path("link");
+        unix_fs::symlink("target", &link).unwrap();
+
+        cache.backup(session, 1, &link).unwrap();
+        // Linux refuses user attributes on symlinks, even to root.
+        let xattrs = Xattrs(vec![("user.note".into(), b"note".to_vec())]);
+        cache
+            .conn
+            .execute("UPDATE files SET xattrs = ?", params![xattrs])
+            .unwrap();
+        fs::remove_file(&link).unwrap();
+
+        let warnings = cache.restore(&link).unwrap();
+        assert!(matches!(
+            warnings.as_slice(),
+            [RestoreWarning::AttributesLost { path, .. }] if *path == link
+        ));
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("target"));
+    }
+}
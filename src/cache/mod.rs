@@ -1,3 +1,16 @@
+pub mod attributes;
+pub mod blob;
+#[allow(clippy::module_inception)]
 pub mod cache;
+pub mod rename;
+pub mod session;
+pub mod time;
+pub mod version;
 
+pub use attributes::*;
+pub use blob::*;
 pub use cache::*;
+pub use rename::*;
+pub use session::*;
+pub use time::*;
+pub use version::*;
@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+/// A rename made during a session, recorded so that reverting can move the
+/// file or tree back rather than rebuild it from snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Whether the two paths swapped places (`RENAME_EXCHANGE`).
+    pub exchange: bool,
+}
@@ -0,0 +1,30 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// One invocation of `undo run`, which every backup it takes belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: i64,
+    /// The program and its arguments. Empty for backups taken before sessions
+    /// were recorded.
+    pub command: Vec<OsString>,
+    pub cwd: PathBuf,
+    pub pid: u32,
+    /// When the run started, in nanoseconds since the epoch.
+    pub started: i64,
+    /// When the run ended, if it has.
+    pub ended: Option<i64>,
+    /// The exit status of the program; 128 plus the signal number if it was killed.
+    pub status: Option<i32>,
+}
+
+impl Session {
+    /// Returns the command line, lossily converted for display.
+    pub fn command_line(&self) -> String {
+        self.command
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
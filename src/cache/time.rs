@@ -0,0 +1,102 @@
+use nix::libc;
+use std::ffi::{CStr, CString};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the current time in nanoseconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as i64)
+}
+
+/// Formats nanoseconds since the epoch as a local date and time.
+pub fn format_time(nanos: i64) -> String {
+    let secs = nanos.div_euclid(1_000_000_000) as libc::time_t;
+    // SAFETY: `tm` is plain data, filled in by `localtime_r`.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call.
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+
+    let mut buf = [0u8; 64];
+    // SAFETY: the format is null-terminated and `buf` is writable for its length.
+    let len = unsafe {
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c"%Y-%m-%d %H:%M:%S".as_ptr(),
+            &tm,
+        )
+    };
+    CStr::from_bytes_until_nul(&buf[..=len])
+        .map(|time| time.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Parses a local date, optionally followed by a time, into nanoseconds since
+/// the epoch.
+///
+/// Accepts `YYYY-MM-DD`, `YYYY-MM-DD HH:MM` and `YYYY-MM-DD HH:MM:SS`.
+pub fn parse_time(time: &str) -> Option<i64> {
+    let time = CString::new(time.trim()).ok()?;
+    [c"%Y-%m-%d %H:%M:%S", c"%Y-%m-%d %H:%M", c"%Y-%m-%d"]
+        .iter()
+        .find_map(|format| {
+            // SAFETY: `tm` is plain data, filled in by `strptime`.
+            let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+            // SAFETY: both strings are null-terminated and `tm` is writable.
+            let end = unsafe { libc::strptime(time.as_ptr(), format.as_ptr(), &mut tm) };
+            // SAFETY: a non-null result points into `time`.
+            if end.is_null() || unsafe { *end } != 0 {
+                return None;
+            }
+
+            // Let `mktime` work out whether daylight saving time applies.
+            tm.tm_isdst = -1;
+            // SAFETY: `tm` is valid and writable.
+            let secs = unsafe { libc::mktime(&mut tm) };
+            (secs != -1).then_some(secs as i64 * 1_000_000_000)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn parses_each_format() {
+        let day = parse_time("2024-03-01").unwrap();
+        assert_eq!(parse_time("2024-03-01 00:00"), Some(day));
+        assert_eq!(parse_time("2024-03-01 00:00:00"), Some(day));
+        assert_eq!(parse_time("2024-03-01 12:34"), Some(day + 45_240 * SECOND));
+        assert_eq!(
+            parse_time("2024-03-01 12:34:56"),
+            Some(day + 45_296 * SECOND)
+        );
+        assert_eq!(parse_time(" 2024-03-01\n"), Some(day));
+    }
+
+    #[test]
+    fn round_trips_through_format_time() {
+        let time = parse_time("2024-03-01 12:34:56").unwrap();
+        assert_eq!(format_time(time), "2024-03-01 12:34:56");
+        assert_eq!(format_time(time + SECOND / 2), "2024-03-01 12:34:56");
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for time in [
+            "",
+            "yesterday",
+            "2024-03-01x",
+            "2024-03-01 12",
+            "2024-03-01 12:34:56 PM",
+            "01/03/2024",
+        ] {
+            assert_eq!(parse_time(time), None, "{:?}", time);
+        }
+    }
+}
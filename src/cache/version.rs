@@ -0,0 +1,57 @@
+/// What a backup held before the path was changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A regular file; the backup stores its content.
+    File,
+    /// A directory; only its metadata is stored.
+    Directory,
+    /// A symbolic link; the backup stores its target.
+    Symlink,
+    /// Nothing existed at the path; reverting removes whatever was created there.
+    Absent,
+    /// A directory whose entries were added or removed; only its timestamps
+    /// are stored. These are not versions of their own and stay out of history.
+    Times,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Directory => "directory",
+            Kind::Symlink => "symlink",
+            Kind::Absent => "absent",
+            Kind::Times => "times",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Kind {
+        match kind {
+            "directory" => Kind::Directory,
+            "symlink" => Kind::Symlink,
+            "absent" => Kind::Absent,
+            "times" => Kind::Times,
+            _ => Kind::File,
+        }
+    }
+}
+
+/// One entry in the history of a path: how it was before a session changed it.
+///
+/// Versions are numbered from 1, oldest first. Reverting to a version drops it
+/// along with every later one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub number: usize,
+    /// The id of the backup in the cache.
+    pub id: i64,
+    /// The session whose change this version precedes.
+    pub session: i64,
+    /// When the backup was taken, in nanoseconds since the epoch.
+    pub taken: i64,
+    pub kind: Kind,
+    /// The length of the stored content, for files and symlinks.
+    pub size: Option<u64>,
+    /// The process that made the change, unless the backup predates recording it.
+    pub pid: Option<u32>,
+}
@@ -0,0 +1,85 @@
+use crate::cache::{format_time, Cache, Kind, Version};
+
+use clap;
+use std::env;
+use std::path::PathBuf;
+
+/// Creates the `history` subcommand.
+pub fn get_subcommand() -> clap::Command {
+    clap::Command::new("history")
+        .about("List the versions of a file that can be reverted to")
+        .long_about(
+            "The `history` subcommand lists every version of a file kept in the cache, oldest first.\n\
+            Each version is the file as it was before a session changed it, and can be restored with\n\
+            `revert --to`."
+        )
+        .arg(
+            clap::Arg::new("file")
+                .help("The file whose history to show.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .required(true)
+        )
+        .after_help(
+            "Example usage:\n\
+            $ undo history somefile.txt\n\
+            Lists the versions of `somefile.txt`.\n\
+            $ undo revert somefile.txt --to 2\n\
+            Reverts `somefile.txt` to its second version."
+        )
+}
+
+/// Handles the `history` subcommand.
+pub fn handle(c: &Cache, matches: &clap::ArgMatches) {
+    let file = matches.get_one::<PathBuf>("file").unwrap();
+    let file_path = if file.is_absolute() {
+        file.to_path_buf()
+    } else {
+        env::current_dir().unwrap().join(file)
+    };
+
+    let (history, sessions) = match c
+        .history(&file_path)
+        .and_then(|history| c.sessions().map(|sessions| (history, sessions)))
+    {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error retrieving history: {}", e);
+            return;
+        }
+    };
+    if history.is_empty() {
+        println!("No versions of {} are tracked.", file_path.display());
+        return;
+    }
+
+    println!("Versions of {}:", file_path.display());
+    for version in history {
+        let command = sessions
+            .iter()
+            .find(|session| session.id == version.session)
+            .map(|session| session.command_line())
+            .unwrap_or_default();
+        let process = version
+            .pid
+            .map(|pid| format!(" (pid {})", pid))
+            .unwrap_or_default();
+        println!(
+            "{:>4}  {}  {:<16}  session {}{}: {}",
+            version.number,
+            format_time(version.taken),
+            describe(&version),
+            version.session,
+            process,
+            command
+        );
+    }
+}
+
+/// Describes what a version held.
+fn describe(version: &Version) -> String {
+    match (version.kind, version.size) {
+        (Kind::File, Some(size)) => format!("file, {} bytes", size),
+        (Kind::Absent, _) => "did not exist".to_string(),
+        (kind, _) => kind.as_str().to_string(),
+    }
+}
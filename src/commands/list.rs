@@ -1,4 +1,4 @@
-use crate::cache::Cache;
+use crate::cache::{format_time, Cache, Session};
 
 use clap;
 
@@ -8,32 +8,65 @@ pub fn get_subcommand() -> clap::Command {
         .about("List all modified files that can be reverted")
         .long_about(
             "The `list` subcommand displays a list of files that have been modified and are currently tracked for undo.\n\
-            These files can be reverted to their previous state using the `revert` subcommand."
+            Files are grouped by the run that changed them, with its command line, working directory,\n\
+            start time and exit status. These files can be reverted to their previous state using the\n\
+            `revert` subcommand, either one by one or a whole run at a time."
         )
         .after_help(
             "Examples:\n\
             $ undo list\n\
             Shows all modified files that can be reverted.\n\
             $ undo revert somefile.txt\n\
-            Reverts the changes made to 'somefile.txt'."
+            Reverts the changes made to 'somefile.txt'.\n\
+            $ undo revert --session 2\n\
+            Reverts the changes made by session 2."
         )
 }
 
 /// Handles the `list` subcommand.
 pub fn handle(c: &Cache) {
-    match c.list() {
-        Ok(files) => {
-            if files.is_empty() {
-                println!("No files are currently tracked for undo.");
-            } else {
-                println!("Modified files:");
+    let sessions = match c.sessions() {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("Error retrieving tracked files: {}", e);
+            return;
+        }
+    };
+    if sessions.is_empty() {
+        println!("No files are currently tracked for undo.");
+        return;
+    }
+
+    for (i, session) in sessions.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        describe(session);
+        match c.list(session.id) {
+            Ok(files) => {
                 for file in files {
-                    println!("{}", file.display());
+                    println!("  {}", file.display());
                 }
             }
+            Err(e) => eprintln!("Error retrieving tracked files: {}", e),
         }
-        Err(e) => {
-            eprintln!("Error retrieving tracked files: {}", e);
-        }
     }
 }
+
+/// Prints the heading of a session's files.
+fn describe(session: &Session) {
+    if session.command.is_empty() {
+        println!("Session {}: recorded before sessions", session.id);
+        return;
+    }
+
+    println!("Session {}: {}", session.id, session.command_line());
+    println!("  Directory: {}", session.cwd.display());
+    println!("  Started:   {}", format_time(session.started));
+    match (session.ended, session.status) {
+        (Some(_), Some(status)) => println!("  Exited:    {}", status),
+        (Some(_), None) => println!("  Exited:    unknown"),
+        (None, _) => println!("  Exited:    still running or interrupted"),
+    }
+    println!("  Files:");
+}
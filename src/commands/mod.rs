@@ -1,4 +1,6 @@
 pub mod clear;
+pub mod history;
 pub mod list;
 pub mod revert;
 pub mod run;
+pub mod verify;
@@ -1,8 +1,8 @@
-use crate::cache::Cache;
+use crate::cache::{parse_time, Cache, CacheError, RestoreWarning};
 
 use clap;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Creates the `revert` subcommand.
 pub fn get_subcommand() -> clap::Command {
@@ -10,32 +10,100 @@ pub fn get_subcommand() -> clap::Command {
         .about("Revert the changes made to a file (or all files)")
         .long_about(
             "The `revert` subcommand allows you to undo the changes made to a file by the `run` subcommand.\n\
-            You can specify a specific file or use `all` to revert all modified files."
+            You can specify a specific file or use `all` to revert all modified files.\n\
+            With `--session`, only the changes made by that run are reverted.\n\
+            Every run that changed a file keeps a version of it; `--to` reverts a file to one of\n\
+            the versions shown by `history`, and `--before` to how it was at a given time."
         )
         .arg(
             clap::Arg::new("file")
                 .help("The file to revert. Use 'all' to revert all modified files.")
-                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .required_unless_present("session")
+        )
+        .arg(
+            clap::Arg::new("session")
+                .long("session")
+                .help("Only revert the changes made by this session, as shown by `list`.")
+                .value_parser(clap::value_parser!(i64))
+                .conflicts_with_all(["to", "before"])
+        )
+        .arg(
+            clap::Arg::new("to")
+                .long("to")
+                .value_name("VERSION")
+                .help("Revert the file to this version, as shown by `history`.")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with("before")
+        )
+        .arg(
+            clap::Arg::new("before")
+                .long("before")
+                .value_name("TIME")
+                .help("Revert to how things were at this local time, e.g. '2024-05-01 14:30'.")
         )
         .after_help(
             "Example usage:\n\
             $ undo revert somefile.txt\n\
             This will revert the changes made to `myfile.txt` by the `run` subcommand.\n\
             $ undo revert all\n\
-            This will revert all modified files."
+            This will revert all modified files.\n\
+            $ undo revert --session 3\n\
+            This will revert the changes made by session 3, leaving other runs' changes alone.\n\
+            $ undo revert somefile.txt --to 2\n\
+            This will revert `somefile.txt` to its second version.\n\
+            $ undo revert all --before '2024-05-01 14:30'\n\
+            This will revert every file changed since then to how it was at that time."
         )
 }
 
 /// Handles the `revert` subcommand.
 pub fn handle(c: &mut Cache, matches: &clap::ArgMatches) {
-    let file = matches.get_one::<String>("file").unwrap();
+    let target = if let Some(&session) = matches.get_one::<i64>("session") {
+        Target::Session(session)
+    } else if let Some(&version) = matches.get_one::<usize>("to") {
+        Target::Version(version)
+    } else if let Some(time) = matches.get_one::<String>("before") {
+        match parse_time(time) {
+            Some(time) => Target::Before(time),
+            None => {
+                eprintln!("Invalid time '{}'; expected YYYY-MM-DD [HH:MM[:SS]]", time);
+                return;
+            }
+        }
+    } else {
+        Target::Oldest
+    };
+    let file = matches
+        .get_one::<PathBuf>("file")
+        .map_or(PathBuf::from("all"), PathBuf::clone);
 
-    if file == "all" {
-        match c.list() {
+    if file.as_os_str() == "all" {
+        let (session, since) = match target {
+            Target::Oldest => (None, None),
+            Target::Session(session) => (Some(session), None),
+            Target::Before(time) => (None, Some(time)),
+            Target::Version(_) => {
+                eprintln!("Versions differ between files; `--to` needs a single file.");
+                return;
+            }
+        };
+        if !undo_renames(c, target, None) {
+            return;
+        }
+        match c.restore_order(session, since) {
             Ok(files) => {
+                if files.is_empty() {
+                    if let Some(session) = session {
+                        eprintln!("No changes recorded for session {}", session);
+                    }
+                }
                 for file in files {
-                    match c.restore(&file) {
-                        Ok(_) => println!("Reverted file: {}", file.display()),
+                    match restore(c, target, &file) {
+                        Ok(warnings) => {
+                            println!("Reverted file: {}", file.display());
+                            warn(&warnings);
+                        }
                         Err(e) => eprintln!("Error reverting file '{}': {}", file.display(), e),
                     }
                 }
@@ -43,16 +111,96 @@ pub fn handle(c: &mut Cache, matches: &clap::ArgMatches) {
             Err(e) => eprintln!("Error retrieving cached changes: {}", e),
         }
     } else {
-        let file_path = if Path::new(file).is_absolute() {
-            Path::new(file).to_path_buf()
+        let file_path = if file.is_absolute() {
+            file.to_path_buf()
         } else {
             let current_dir = env::current_dir().unwrap();
             current_dir.join(file)
         };
 
-        match c.restore(&file_path) {
-            Ok(_) => println!("Reverted file: {}", file_path.display()),
+        if !undo_renames(c, target, Some(&file_path)) {
+            return;
+        }
+        match restore(c, target, &file_path) {
+            Ok(warnings) => {
+                println!("Reverted file: {}", file_path.display());
+                warn(&warnings);
+            }
             Err(e) => eprintln!("Error reverting file '{}': {}", file_path.display(), e),
         }
     }
 }
+
+/// Which version of a file to revert to.
+#[derive(Clone, Copy)]
+enum Target {
+    /// From before any session changed it.
+    Oldest,
+    /// From before the given session changed it.
+    Session(i64),
+    /// The given version of its history.
+    Version(usize),
+    /// As it was at the given time, in nanoseconds since the epoch.
+    Before(i64),
+}
+
+/// Restores `file` to the version picked by `target`.
+fn restore(c: &mut Cache, target: Target, file: &Path) -> Result<Vec<RestoreWarning>, CacheError> {
+    match target {
+        Target::Oldest => c.restore(file),
+        Target::Session(session) => c.restore_session(session, file),
+        Target::Version(version) => c.restore_version(file, version),
+        Target::Before(time) => c.restore_before(file, time),
+    }
+}
+
+/// Moves back whatever the changes being reverted renamed, from or to `file`
+/// if given. This has to come before restoring backups, which only hold the
+/// metadata of directories.
+///
+/// Returns whether reverting can go ahead.
+fn undo_renames(c: &mut Cache, target: Target, file: Option<&Path>) -> bool {
+    let (session, since) = match target {
+        Target::Oldest => (None, None),
+        Target::Session(session) => (Some(session), None),
+        Target::Before(time) => (None, Some(time)),
+        Target::Version(version) => {
+            let history = file.map(|file| c.history(file)).transpose();
+            match history.ok().flatten().and_then(|history| {
+                history
+                    .get(version.wrapping_sub(1))
+                    .map(|version| version.taken)
+            }) {
+                Some(taken) => (None, Some(taken)),
+                // Restoring reports the missing version.
+                None => return true,
+            }
+        }
+    };
+
+    match c.undo_renames(session, since, file) {
+        Ok(renames) => {
+            for rename in renames {
+                let arrow = if rename.exchange { "<->" } else { "->" };
+                println!(
+                    "Moved back: {} {} {}",
+                    rename.to.display(),
+                    arrow,
+                    rename.from.display()
+                );
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Error moving back renamed files: {}", e);
+            false
+        }
+    }
+}
+
+/// Reports what a restore could not bring back exactly.
+fn warn(warnings: &[RestoreWarning]) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
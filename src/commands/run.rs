@@ -1,10 +1,18 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, Rename};
 use crate::tracer;
 
 use clap;
+use nix::errno::Errno;
 use nix::sys::ptrace;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::Pid;
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{geteuid, Pid};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process;
 
 /// Creates the `run` subcommand.
@@ -17,44 +25,109 @@ pub fn get_subcommand() -> clap::Command {
         )
         .arg(
             clap::arg!([program] "Command to run")
+                .value_parser(clap::value_parser!(OsString))
                 .required(true)
                 .help("Command to execute, e.g., nano, vim, etc.")
         )
         .arg(
             clap::arg!([args]... "Arguments to passed to the command")
+                .value_parser(clap::value_parser!(OsString))
                 .help("Arguments passed to the specified command.")
         )
 }
 
+/// A syscall a tracee has entered but not yet returned from.
+struct InFlight {
+    /// The change the syscall will make if it succeeds.
+    change: Option<tracer::Change>,
+    /// The paths newly backed up when the syscall was entered.
+    backed_up: Vec<PathBuf>,
+    /// Whether the change only touches devices, FIFOs or sockets, as writing
+    /// to `/dev/null` does, and so is not worth reporting.
+    quiet: bool,
+}
+
+/// The backups taken by one session.
+struct Backups<'a> {
+    cache: &'a Cache,
+    session: i64,
+    /// Every path the session has touched, whether or not it was backed up.
+    seen: HashSet<PathBuf>,
+    /// Directories whose entries the session has changed, and whose times
+    /// alone were backed up for it.
+    timed: HashSet<PathBuf>,
+}
+
 /// Handles the `run` subcommand.
-pub fn handle(_c: &Cache, matches: &clap::ArgMatches) {
-    match process::Command::new(matches.get_one::<String>("program").unwrap())
-        .args(
-            matches
-                .get_many::<String>("args")
-                .map(|s| s.collect::<Vec<_>>())
-                .unwrap_or_default(),
-        )
+pub fn handle(c: &Cache, matches: &clap::ArgMatches) {
+    let program = matches.get_one::<OsString>("program").unwrap();
+    let args = matches
+        .get_many::<OsString>("args")
+        .map(|s| s.cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut command = process::Command::new(program);
+    command
+        .args(&args)
         .stdin(std::process::Stdio::inherit())
         .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .spawn()
-    {
-        Ok(child_process) => {
+        .stderr(std::process::Stdio::inherit());
+
+    // Built up front: nothing may allocate between fork and exec.
+    let filter = tracer::SeccompFilter::new();
+
+    // SAFETY: only async-signal-safe calls are made between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            // Become a tracee before exec. The kernel then stops the program with
+            // SIGTRAP before its first instruction, so no syscall runs untraced.
+            ptrace::traceme()?;
+            // Without the filter every syscall stops; that is slow but complete.
+            let _ = filter.install();
+            Ok(())
+        });
+    }
+
+    match command.spawn() {
+        Ok(mut child_process) => {
+            // Ctrl-C and Ctrl-\ reach the traced program too. Leave it to the
+            // program whether to exit, and keep tracing until it does.
+            // SAFETY: ignoring a signal installs no handler.
+            unsafe {
+                let _ = signal::signal(Signal::SIGINT, SigHandler::SigIgn);
+                let _ = signal::signal(Signal::SIGQUIT, SigHandler::SigIgn);
+            }
+
+            let command_line: Vec<OsString> =
+                std::iter::once(program.clone()).chain(args).collect();
+            let cwd = env::current_dir().unwrap_or_default();
+            let session = match c.start_session(&command_line, &cwd, child_process.id()) {
+                Ok(session) => session,
+                Err(e) => {
+                    eprintln!("Failed to record session: {}", e);
+                    let _ = child_process.kill();
+                    process::exit(1);
+                }
+            };
+
+            let mut backups = Backups {
+                cache: c,
+                session,
+                seen: HashSet::new(),
+                timed: HashSet::new(),
+            };
             let child_pid = Pid::from_raw(child_process.id() as i32);
-            ptrace::attach(child_pid).unwrap();
-            loop {
-                match waitpid(child_pid, None).unwrap() {
-                    WaitStatus::Stopped(pid, _) => {
-                        if let Ok(path) = tracer::sniff(pid) {
-                            println!("Path: {}", path);
-                        }
-                        ptrace::syscall(pid, None).unwrap();
+            match trace(&mut backups, child_pid) {
+                Ok(status) => {
+                    if let Err(e) = c.end_session(session, status) {
+                        eprintln!("Failed to record end of session: {}", e);
                     }
-                    WaitStatus::Exited(_, _) => {
-                        break;
-                    }
-                    _ => {}
+                }
+                Err(e) => {
+                    eprintln!("Failed to trace command: {}", e);
+                    let _ = child_process.kill();
+                    let _ = c.end_session(session, None);
+                    process::exit(1);
                 }
             }
         }
@@ -64,3 +137,265 @@ pub fn handle(_c: &Cache, matches: &clap::ArgMatches) {
         }
     }
 }
+
+/// Traces `root` and every process it spawns until all of them have exited.
+///
+/// Returns the exit status of `root`, or 128 plus the signal number if it was
+/// killed.
+fn trace(backups: &mut Backups, root: Pid) -> Result<Option<i32>, Errno> {
+    // The child stops right after exec; set options before it resumes.
+    // Descendants inherit the options, so the whole tree is traced. Tracees
+    // are killed if the tracer dies: their seccomp filter would otherwise fail
+    // every traced syscall with ENOSYS once nobody is there to handle it.
+    waitpid(root, Some(WaitPidFlag::__WALL))?;
+    ptrace::setoptions(
+        root,
+        ptrace::Options::PTRACE_O_TRACESYSGOOD
+            | ptrace::Options::PTRACE_O_TRACESECCOMP
+            | ptrace::Options::PTRACE_O_TRACEFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORK
+            | ptrace::Options::PTRACE_O_TRACECLONE
+            | ptrace::Options::PTRACE_O_TRACEEXEC
+            | ptrace::Options::PTRACE_O_EXITKILL,
+    )?;
+
+    // Under the seccomp filter, only the syscalls it hands over stop the tracee,
+    // so everything else can run at full speed with PTRACE_CONT.
+    let filtered = tracer::is_filtered(root);
+    if !filtered {
+        eprintln!(
+            "Could not install the seccomp filter; tracing every syscall instead, which is slower"
+        );
+    }
+    let resume = |pid: Pid, signal: Option<Signal>| {
+        let _ = if filtered {
+            ptrace::cont(pid, signal)
+        } else {
+            ptrace::syscall(pid, signal)
+        };
+    };
+    // Writes through inherited fds are never seen, so back their files up now.
+    for path in tracer::open_for_writing(root) {
+        backups.snapshot(&path, root);
+    }
+    resume(root, None);
+
+    let mut exit_status = None;
+    let mut in_flight: HashMap<Pid, InFlight> = HashMap::new();
+    // Tracees whose initial SIGSTOP has already been consumed.
+    let mut started = HashSet::from([root]);
+    loop {
+        let status = match waitpid(None, Some(WaitPidFlag::__WALL)) {
+            Ok(status) => status,
+            Err(Errno::ECHILD) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                eprintln!("Failed to wait for traced process: {}", e);
+                break;
+            }
+        };
+
+        match status {
+            WaitStatus::PtraceSyscall(pid) => match in_flight.remove(&pid) {
+                None => {
+                    in_flight.insert(pid, enter(backups, pid));
+                    let _ = ptrace::syscall(pid, None);
+                }
+                Some(syscall) => {
+                    finish(backups, pid, syscall);
+                    resume(pid, None);
+                }
+            },
+            WaitStatus::PtraceEvent(pid, _, event)
+                if event == ptrace::Event::PTRACE_EVENT_SECCOMP as i32 =>
+            {
+                // The seccomp stop happens on syscall entry. Step to the
+                // syscall-exit stop to learn whether it succeeded.
+                in_flight.insert(pid, enter(backups, pid));
+                let _ = ptrace::syscall(pid, None);
+            }
+            WaitStatus::PtraceEvent(pid, _, event) => {
+                if event == ptrace::Event::PTRACE_EVENT_EXEC as i32 {
+                    // A non-leader thread that execs takes over the leader's pid,
+                    // carrying its in-progress execve with it.
+                    if let Ok(former) = ptrace::getevent(pid) {
+                        let former = Pid::from_raw(former as i32);
+                        if former != pid {
+                            in_flight.remove(&pid);
+                            if let Some(syscall) = in_flight.remove(&former) {
+                                in_flight.insert(pid, syscall);
+                            }
+                        }
+                    }
+                }
+                resume(pid, None);
+            }
+            WaitStatus::Stopped(pid, Signal::SIGSTOP) if started.insert(pid) => {
+                // New children are auto-attached and start with a SIGSTOP that
+                // must not be delivered.
+                resume(pid, None);
+            }
+            WaitStatus::Stopped(pid, signal) => {
+                started.insert(pid);
+                resume(pid, Some(signal));
+            }
+            WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => {
+                if pid == root {
+                    exit_status = match status {
+                        WaitStatus::Exited(_, code) => Some(code),
+                        WaitStatus::Signaled(_, signal, _) => Some(128 + signal as i32),
+                        _ => None,
+                    };
+                }
+                in_flight.remove(&pid);
+                started.remove(&pid);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(exit_status)
+}
+
+/// Inspects a syscall a tracee is about to make.
+///
+/// The tracee is stopped before the syscall runs, so this is the last chance
+/// to capture the original content of the files it is about to change.
+fn enter(backups: &mut Backups, pid: Pid) -> InFlight {
+    let regs = tracer::peek(pid).ok();
+    let change = regs.and_then(|regs| tracer::sniff(pid, &regs).ok());
+    let mut backed_up = Vec::new();
+    let quiet = change
+        .as_ref()
+        .is_some_and(|change| change.paths().into_iter().all(special));
+    if let Some(change) = &change {
+        for path in change.paths() {
+            if backups.snapshot(path, pid) {
+                backed_up.push(path.to_path_buf());
+            }
+        }
+        for dir in change.directories().into_iter().filter(|dir| owns(dir)) {
+            if backups.snapshot_times(dir, pid) {
+                backed_up.push(dir.to_path_buf());
+            }
+        }
+    }
+    InFlight {
+        change,
+        backed_up,
+        quiet,
+    }
+}
+
+/// Reports whether `path` is a device, FIFO or socket, which has no content
+/// worth saving.
+fn special(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|metadata| {
+        let file_type = metadata.file_type();
+        !(file_type.is_file() || file_type.is_dir() || file_type.is_symlink())
+    })
+}
+
+/// Reports whether the caller may restore the timestamps of `dir`.
+///
+/// Setting them to anything but the current time takes ownership or root, so
+/// directories such as `/tmp` are not worth backing up just for their times.
+fn owns(dir: &Path) -> bool {
+    let euid = geteuid();
+    euid.is_root()
+        || dir
+            .metadata()
+            .is_ok_and(|metadata| metadata.uid() == euid.as_raw())
+}
+
+impl Backups<'_> {
+    /// Backs up the file, directory or symlink at `path` the first time this
+    /// session touches it.
+    ///
+    /// A path that does not exist yet is backed up as a tombstone. Backups taken
+    /// by earlier runs are kept alongside, and the backup records `pid` as the
+    /// process that changed the path. Returns whether a new backup was taken.
+    fn snapshot(&mut self, path: &Path, pid: Pid) -> bool {
+        if !self.seen.insert(path.to_path_buf()) || special(path) {
+            return false;
+        }
+
+        match self.cache.backup(self.session, pid.as_raw() as u32, path) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("Failed to back up '{}': {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Backs up the times of the directory `dir` the first time this session
+    /// adds or removes entries in it, unless it was backed up in full already.
+    ///
+    /// Returns whether a new backup was taken.
+    fn snapshot_times(&mut self, dir: &Path, pid: Pid) -> bool {
+        if self.seen.contains(dir) || !self.timed.insert(dir.to_path_buf()) {
+            return false;
+        }
+
+        match self
+            .cache
+            .backup_times(self.session, pid.as_raw() as u32, dir)
+        {
+            Ok(taken) => taken,
+            Err(e) => {
+                eprintln!("Failed to back up '{}': {}", dir.display(), e);
+                false
+            }
+        }
+    }
+
+    /// Records a rename that has succeeded, so that reverting moves it back.
+    fn record_rename(&self, change: &tracer::Change) {
+        let (from, to, exchange) = match change {
+            tracer::Change::Renamed { from, to } => (from, to, false),
+            tracer::Change::Exchanged { from, to } => (from, to, true),
+            _ => return,
+        };
+        let rename = Rename {
+            from: from.clone(),
+            to: to.clone(),
+            exchange,
+        };
+        if let Err(e) = self.cache.record_rename(self.session, &rename) {
+            eprintln!("Failed to record rename of '{}': {}", from.display(), e);
+        }
+    }
+
+    /// Drops the backup of `path`, so that the next touch takes a fresh one.
+    fn discard(&mut self, path: &Path) {
+        if let Err(e) = self.cache.discard(self.session, path) {
+            eprintln!("Failed to discard '{}': {}", path.display(), e);
+        }
+        self.seen.remove(path);
+        self.timed.remove(path);
+    }
+}
+
+/// Records the outcome of a syscall once the tracee returns from it.
+///
+/// A failed syscall changed nothing, so any backup taken on entry is dropped.
+fn finish(backups: &mut Backups, pid: Pid, syscall: InFlight) {
+    match tracer::retval(pid) {
+        Ok(ret) if (-4095..0).contains(&ret) => {
+            for path in syscall.backed_up {
+                backups.discard(&path);
+            }
+        }
+        Ok(_) => {
+            if let Some(change) = syscall.change {
+                backups.record_rename(&change);
+                // The program's own output goes to stdout; keep out of its way.
+                if !syscall.quiet {
+                    eprintln!("[{}] {}", pid, change);
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to read syscall result: {}", e),
+    }
+}
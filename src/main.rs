@@ -3,7 +3,7 @@ mod commands;
 mod tracer;
 
 use cache::Cache;
-use commands::{clear, list, revert, run};
+use commands::{clear, history, list, revert, run, verify};
 
 use clap::Command;
 
@@ -28,16 +28,20 @@ fn main() {
         )
         .infer_long_args(true)
         .subcommand(clear::get_subcommand())
+        .subcommand(history::get_subcommand())
         .subcommand(list::get_subcommand())
         .subcommand(revert::get_subcommand())
         .subcommand(run::get_subcommand())
+        .subcommand(verify::get_subcommand())
         .get_matches();
 
     match matches.subcommand() {
         Some(("clear", _)) => clear::handle(&mut cache),
+        Some(("history", sub_m)) => history::handle(&cache, sub_m),
         Some(("list", _)) => list::handle(&cache),
         Some(("revert", sub_m)) => revert::handle(&mut cache, sub_m),
         Some(("run", sub_m)) => run::handle(&cache, sub_m),
+        Some(("verify", _)) => verify::handle(&cache),
         _ => {
             eprintln!("Invalid command.");
             std::process::exit(1);
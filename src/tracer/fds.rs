@@ -0,0 +1,103 @@
+use nix::libc;
+use nix::unistd::Pid;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// Returns the path `fd` of `pid` refers to, as `/proc/<pid>/fd` shows it.
+///
+/// Fd-based syscalls such as `ftruncate` or `fchmod` only carry a number, so
+/// the file is looked up while the syscall is stopped on entry; no fd table
+/// has to be kept in step with `dup`, `fcntl` or `close`. Pipes, sockets and
+/// other fds that are not backed by a path yield `None`, as do files that
+/// were deleted or replaced since they were opened.
+pub fn fd_path(pid: Pid, fd: i32) -> Option<PathBuf> {
+    let link = format!("/proc/{}/fd/{}", pid, fd);
+    let path = fs::read_link(&link).ok()?;
+    let opened = fs::metadata(&link).ok()?;
+
+    // A deleted file shows up as "<path> (deleted)", and another file may
+    // have taken its name since.
+    let current = fs::symlink_metadata(&path).ok()?;
+    let same = (current.dev(), current.ino()) == (opened.dev(), opened.ino());
+    (path.is_absolute() && same).then_some(path)
+}
+
+/// Returns the regular files `pid` has open for writing.
+///
+/// A traced program may write through fds it inherited without ever opening
+/// them, as `cmd 3>>log` allows, so these are backed up before it starts.
+pub fn open_for_writing(pid: Pid) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(format!("/proc/{}/fdinfo", pid))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let flags = fd_flags(&fs::read_to_string(entry.path()).ok()?)?;
+            if flags & libc::O_ACCMODE == libc::O_RDONLY {
+                return None;
+            }
+            fd_path(pid, fd).filter(|path| path.is_file())
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Parses the open flags out of a `/proc/<pid>/fdinfo/<fd>` file, where they
+/// are given in octal.
+fn fd_flags(fdinfo: &str) -> Option<i32> {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{File, OpenOptions};
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn parses_octal_flags() {
+        let fdinfo = "pos:\t0\nflags:\t02002001\nmnt_id:\t25\nino:\t12\n";
+        assert_eq!(
+            fd_flags(fdinfo),
+            Some(libc::O_WRONLY | libc::O_APPEND | libc::O_CLOEXEC)
+        );
+        assert_eq!(fd_flags("pos:\t0\n"), None);
+    }
+
+    #[test]
+    fn finds_files_open_for_writing() {
+        let dir = env::temp_dir().join(format!("undo-fds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+        let (written, read) = (dir.join("written"), dir.join("read"));
+        let writer = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&written)
+            .unwrap();
+        fs::write(&read, "").unwrap();
+        let reader = File::open(&read).unwrap();
+
+        let open = open_for_writing(Pid::this());
+        assert!(open.contains(&written));
+        assert!(!open.contains(&read));
+        assert_eq!(fd_path(Pid::this(), reader.as_raw_fd()), Some(read.clone()));
+
+        // A new file under the same name is not the one that is open.
+        fs::remove_file(&read).unwrap();
+        assert_eq!(fd_path(Pid::this(), reader.as_raw_fd()), None);
+        fs::write(&read, "").unwrap();
+        assert_eq!(fd_path(Pid::this(), reader.as_raw_fd()), None);
+
+        drop(writer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
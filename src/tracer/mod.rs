@@ -1,7 +1,17 @@
+pub mod fds;
 pub mod peek;
+pub mod regs;
+pub mod retval;
+pub mod seccomp;
 pub mod sniff;
 pub mod string_at;
+pub mod syscall;
 
+pub use fds::*;
 pub use peek::*;
+pub use regs::*;
+pub use retval::*;
+pub use seccomp::*;
 pub use sniff::*;
 pub use string_at::*;
+pub use syscall::*;
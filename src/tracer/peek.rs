@@ -1,24 +1,83 @@
-use nix::libc::user_regs_struct;
+use crate::tracer::{SyscallRegs, AUDIT_ARCH};
+
+use nix::errno::Errno;
+use nix::libc;
 use nix::sys::ptrace;
 use nix::unistd::Pid;
 use nix::Error;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-/// Retrieves the register state of a process.
-pub fn peek(pid: Pid) -> Result<user_regs_struct, Error> {
-    #[cfg(target_arch = "x86_64")]
-    {
-        return ptrace::getregs(pid).map_err(|e| e);
-    }
+/// Set once the kernel has rejected `PTRACE_GET_SYSCALL_INFO` (before Linux 5.3).
+static NO_SYSCALL_INFO: AtomicBool = AtomicBool::new(false);
 
-    #[cfg(target_arch = "aarch64")]
-    {
-        let regset_type = nix::sys::ptrace::RegSet::NT_PRSTATUS;
-        let regs = ptrace::getregset(pid, regset_type)?;
-        if let Some(regs) = regs {
-            let user_regs: user_regs_struct = unsafe { mem::transmute(regs) };
-            return Ok(user_regs);
-        } else {
-            return Err(Error::from(nix::errno::Errno::EPERM));
+/// Retrieves the syscall registers of a process stopped at a syscall.
+///
+/// `PTRACE_GET_SYSCALL_INFO` is preferred where the kernel supports it, since it
+/// reports the syscall the same way on every architecture. Older kernels fall
+/// back to reading the general-purpose registers.
+///
+/// Syscalls made through a foreign ABI, such as 32-bit x86 programs on x86_64,
+/// are numbered differently and are rejected with `EINVAL`.
+pub fn peek(pid: Pid) -> Result<SyscallRegs, Error> {
+    if !NO_SYSCALL_INFO.load(Ordering::Relaxed) {
+        match syscall_info(pid) {
+            Ok(Some(info)) if info.arch != AUDIT_ARCH => return Err(Errno::EINVAL),
+            Ok(Some(info)) => return Ok(SyscallRegs::from(info)),
+            Ok(None) => {}
+            Err(Errno::EIO) | Err(Errno::EINVAL) => NO_SYSCALL_INFO.store(true, Ordering::Relaxed),
+            Err(e) => return Err(e),
         }
     }
+
+    let regs = ptrace::getregs(pid)?;
+    if !native(&regs) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(SyscallRegs::from(regs))
+}
+
+/// Tells whether the registers of a stopped tracee belong to a native syscall.
+#[cfg(target_arch = "x86_64")]
+fn native(regs: &libc::user_regs_struct) -> bool {
+    // 32-bit processes run in the __USER32_CS segment. An `int 0x80` made by
+    // 64-bit code cannot be told apart without PTRACE_GET_SYSCALL_INFO.
+    regs.cs != 0x23
+}
+
+/// Tells whether the registers of a stopped tracee belong to a native syscall.
+#[cfg(target_arch = "aarch64")]
+fn native(regs: &libc::user_regs_struct) -> bool {
+    // PSR_MODE32_BIT is set for AArch32 tasks.
+    regs.pstate & 0x10 == 0
+}
+
+/// Tells whether the registers of a stopped tracee belong to a native syscall.
+#[cfg(target_arch = "riscv64")]
+fn native(_regs: &libc::user_regs_struct) -> bool {
+    // 32-bit compat tasks need Linux 5.19, which has PTRACE_GET_SYSCALL_INFO.
+    true
+}
+
+/// Reads `PTRACE_GET_SYSCALL_INFO`, or `None` if the process is not at a syscall stop.
+fn syscall_info(pid: Pid) -> Result<Option<libc::ptrace_syscall_info>, Error> {
+    let mut info = mem::MaybeUninit::<libc::ptrace_syscall_info>::zeroed();
+    // SAFETY: the kernel writes at most `size_of::<ptrace_syscall_info>()` bytes.
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            mem::size_of::<libc::ptrace_syscall_info>(),
+            info.as_mut_ptr(),
+        )
+    };
+    Errno::result(res)?;
+
+    // SAFETY: the buffer was zeroed and then filled in by the kernel.
+    let info = unsafe { info.assume_init() };
+    if info.op == libc::PTRACE_SYSCALL_INFO_NONE {
+        Ok(None)
+    } else {
+        Ok(Some(info))
+    }
 }
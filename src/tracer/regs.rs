@@ -0,0 +1,136 @@
+use nix::libc::{self, user_regs_struct};
+
+/// The syscall registers of a stopped tracee, independent of the architecture.
+///
+/// `nr` and `arg` are meaningful at syscall-entry stops, `ret` at syscall-exit
+/// stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallRegs {
+    nr: u64,
+    args: [u64; 6],
+    ret: i64,
+}
+
+impl SyscallRegs {
+    /// Builds registers by hand, for tests that do not have a tracee to read.
+    #[cfg(test)]
+    pub fn new(nr: u64, args: [u64; 6], ret: i64) -> Self {
+        SyscallRegs { nr, args, ret }
+    }
+
+    /// Returns the number of the syscall, in the host architecture's numbering.
+    pub fn nr(&self) -> u64 {
+        self.nr
+    }
+
+    /// Returns the `n`th syscall argument.
+    pub fn arg(&self, n: usize) -> u64 {
+        self.args[n]
+    }
+
+    /// Returns the syscall's return value; failed syscalls return `-errno`.
+    pub fn ret(&self) -> i64 {
+        self.ret
+    }
+}
+
+impl From<user_regs_struct> for SyscallRegs {
+    #[cfg(target_arch = "x86_64")]
+    fn from(regs: user_regs_struct) -> Self {
+        SyscallRegs {
+            nr: regs.orig_rax,
+            args: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+            ret: regs.rax as i64,
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn from(regs: user_regs_struct) -> Self {
+        // x0 holds the first argument on entry and the return value on exit.
+        SyscallRegs {
+            nr: regs.regs[8],
+            args: [
+                regs.regs[0],
+                regs.regs[1],
+                regs.regs[2],
+                regs.regs[3],
+                regs.regs[4],
+                regs.regs[5],
+            ],
+            ret: regs.regs[0] as i64,
+        }
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn from(regs: user_regs_struct) -> Self {
+        // a0 holds the first argument on entry and the return value on exit.
+        SyscallRegs {
+            nr: regs.a7,
+            args: [regs.a0, regs.a1, regs.a2, regs.a3, regs.a4, regs.a5],
+            ret: regs.a0 as i64,
+        }
+    }
+}
+
+impl From<libc::ptrace_syscall_info> for SyscallRegs {
+    fn from(info: libc::ptrace_syscall_info) -> Self {
+        // SAFETY: `op` tells which member of the union the kernel filled in.
+        unsafe {
+            match info.op {
+                libc::PTRACE_SYSCALL_INFO_ENTRY => SyscallRegs {
+                    nr: info.u.entry.nr,
+                    args: info.u.entry.args,
+                    ret: -(libc::ENOSYS as i64),
+                },
+                libc::PTRACE_SYSCALL_INFO_SECCOMP => SyscallRegs {
+                    nr: info.u.seccomp.nr,
+                    args: info.u.seccomp.args,
+                    ret: -(libc::ENOSYS as i64),
+                },
+                libc::PTRACE_SYSCALL_INFO_EXIT => SyscallRegs {
+                    nr: u64::MAX,
+                    args: [0; 6],
+                    ret: info.u.exit.sval,
+                },
+                _ => SyscallRegs {
+                    nr: u64::MAX,
+                    args: [0; 6],
+                    ret: -(libc::ENOSYS as i64),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_info_carries_only_the_return_value() {
+        // SAFETY: ptrace_syscall_info is plain old data.
+        let mut info: libc::ptrace_syscall_info = unsafe { std::mem::zeroed() };
+        info.op = libc::PTRACE_SYSCALL_INFO_EXIT;
+        info.u.exit.sval = -(libc::ENOENT as i64);
+
+        let regs = SyscallRegs::from(info);
+        assert_eq!(
+            regs,
+            SyscallRegs::new(u64::MAX, [0; 6], -(libc::ENOENT as i64))
+        );
+    }
+
+    #[test]
+    fn seccomp_info_carries_the_arguments() {
+        // SAFETY: ptrace_syscall_info is plain old data.
+        let mut info: libc::ptrace_syscall_info = unsafe { std::mem::zeroed() };
+        info.op = libc::PTRACE_SYSCALL_INFO_SECCOMP;
+        info.u.seccomp.nr = 257;
+        info.u.seccomp.args = [1, 2, 3, 4, 5, 6];
+
+        let regs = SyscallRegs::from(info);
+        assert_eq!(regs.nr(), 257);
+        assert_eq!(regs.arg(2), 3);
+        assert_eq!(regs.ret(), -(libc::ENOSYS as i64));
+    }
+}
@@ -0,0 +1,11 @@
+use crate::tracer::peek;
+
+use nix::unistd::Pid;
+use nix::Error;
+
+/// Retrieves the return value of the syscall a process is exiting from.
+///
+/// Must only be called at a syscall-exit stop; failed syscalls return `-errno`.
+pub fn retval(pid: Pid) -> Result<i64, Error> {
+    Ok(peek(pid)?.ret())
+}
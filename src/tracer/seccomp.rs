@@ -0,0 +1,351 @@
+use crate::tracer::{Syscall, AUDIT_ARCH};
+
+use nix::errno::Errno;
+use nix::libc::{self, sock_filter, sock_fprog};
+use nix::unistd::Pid;
+use std::fs;
+
+/// Offsets into `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+/// A seccomp-BPF program that hands only file-mutating syscalls to the tracer.
+///
+/// Every syscall `Syscall::from_nr` recognizes returns `SECCOMP_RET_TRACE`, which
+/// stops the tracee with `PTRACE_EVENT_SECCOMP`; everything else is allowed
+/// without a stop. Syscalls that only sometimes matter, like `mmap`, have their
+/// arguments checked first. Syscalls made through a foreign ABI (such as 32-bit
+/// x86 programs on x86_64) are allowed, since their numbers mean something else.
+pub struct SeccompFilter {
+    program: Vec<sock_filter>,
+}
+
+impl SeccompFilter {
+    /// Builds the filter for the host architecture.
+    pub fn new() -> Self {
+        let mut program = vec![
+            load(ARCH_OFFSET),
+            jump_if(AUDIT_ARCH, 1, 0),
+            ret(libc::SECCOMP_RET_ALLOW),
+            load(NR_OFFSET),
+        ];
+
+        // No syscall table comes close to this many entries.
+        let mut traced = Vec::new();
+        for nr in 0..1024 {
+            let Some(syscall) = Syscall::from_nr(nr as u64) else {
+                continue;
+            };
+            match arguments(syscall) {
+                // Each check ends in a return, so falling into it is final.
+                Some(check) => {
+                    program.push(jump_if(nr, 0, check.len() as u8));
+                    program.extend(check);
+                }
+                None => traced.push(nr),
+            }
+        }
+
+        for (i, &nr) in traced.iter().enumerate() {
+            // Jump over the remaining comparisons and the ALLOW to the TRACE.
+            let skip = (traced.len() - i) as u8;
+            program.push(jump_if(nr, skip, 0));
+        }
+        program.push(ret(libc::SECCOMP_RET_ALLOW));
+        program.push(ret(libc::SECCOMP_RET_TRACE));
+
+        SeccompFilter { program }
+    }
+
+    /// Installs the filter on the calling thread.
+    ///
+    /// Called in the child between fork and exec, so it must not allocate.
+    /// Unprivileged callers have to set `no_new_privs` first, which stops setuid
+    /// programs from gaining privileges; that is only done when necessary.
+    pub fn install(&self) -> Result<(), Errno> {
+        let prog = sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut sock_filter,
+        };
+
+        let set_filter = || {
+            // SAFETY: `prog` points to a valid program that outlives the call.
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_seccomp,
+                    libc::SECCOMP_SET_MODE_FILTER,
+                    0,
+                    &prog as *const sock_fprog,
+                )
+            };
+            Errno::result(res).map(drop)
+        };
+
+        match set_filter() {
+            Err(Errno::EACCES) => {
+                // SAFETY: PR_SET_NO_NEW_PRIVS takes no pointers.
+                let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+                Errno::result(res)?;
+                set_filter()
+            }
+            result => result,
+        }
+    }
+}
+
+/// Reports whether a child of the calling process runs under the filter.
+///
+/// The child falls back to running unfiltered if the filter cannot be
+/// installed, in which case every syscall has to be traced. Filters are
+/// inherited, and inside a container the tracer usually runs under one
+/// already, so the child must have more of them than the tracer has.
+pub fn is_filtered(pid: Pid) -> bool {
+    match (filters(pid), filters(Pid::this())) {
+        (Some(child), Some(own)) => child > own,
+        _ => false,
+    }
+}
+
+/// Reads how many seccomp filters `pid` runs under from `/proc/<pid>/status`.
+///
+/// Kernels before 5.9 do not say, so the filter is not trusted there.
+fn filters(pid: Pid) -> Option<u32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Seccomp_filters:"))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Builds the argument check for a syscall that only matters with some arguments.
+///
+/// The check returns `SECCOMP_RET_TRACE` when the syscall may change a file and
+/// `SECCOMP_RET_ALLOW` otherwise. Syscalls traced whatever their arguments
+/// return `None`.
+fn arguments(syscall: Syscall) -> Option<Vec<sock_filter>> {
+    let check = match syscall {
+        // Only shared, writable mappings of a file write back to it.
+        Syscall::Mmap => vec![
+            load_arg(2),
+            jump_set(libc::PROT_WRITE as u32, 0, 4),
+            load_arg(3),
+            // MAP_SHARED_VALIDATE includes the MAP_SHARED bit; MAP_PRIVATE does not.
+            jump_set(libc::MAP_SHARED as u32, 0, 2),
+            jump_set(libc::MAP_ANONYMOUS as u32, 1, 0),
+            ret(libc::SECCOMP_RET_TRACE),
+            ret(libc::SECCOMP_RET_ALLOW),
+        ],
+        // Which mapping is affected is only known from /proc, so any
+        // mprotect adding write access is looked at.
+        Syscall::Mprotect => vec![
+            load_arg(2),
+            jump_set(libc::PROT_WRITE as u32, 0, 1),
+            ret(libc::SECCOMP_RET_TRACE),
+            ret(libc::SECCOMP_RET_ALLOW),
+        ],
+        // Opening read-only changes nothing, unless it creates or truncates.
+        Syscall::Open => read_only_open(1),
+        Syscall::Openat => read_only_open(2),
+        _ => return None,
+    };
+    Some(check)
+}
+
+/// Builds the check for an open call whose flags are argument `flags`.
+///
+/// `O_RDONLY` is zero, so any access mode bit means writing.
+fn read_only_open(flags: u32) -> Vec<sock_filter> {
+    vec![
+        load_arg(flags),
+        jump_set(
+            (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u32,
+            0,
+            1,
+        ),
+        ret(libc::SECCOMP_RET_TRACE),
+        ret(libc::SECCOMP_RET_ALLOW),
+    ]
+}
+
+/// Loads the low 32 bits of the `n`th syscall argument.
+///
+/// Every supported architecture is little-endian, so they come first.
+fn load_arg(n: u32) -> sock_filter {
+    load(ARGS_OFFSET + 8 * n)
+}
+
+/// `BPF_LD | BPF_W | BPF_ABS`: loads the 32-bit word at `offset`.
+fn load(offset: u32) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+/// `BPF_JMP | BPF_JEQ | BPF_K`: skips `jt` instructions if equal to `value`, else `jf`.
+fn jump_if(value: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k: value,
+    }
+}
+
+/// `BPF_JMP | BPF_JSET | BPF_K`: skips `jt` instructions if any bit of `mask` is set, else `jf`.
+fn jump_set(mask: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k: mask,
+    }
+}
+
+/// `BPF_RET | BPF_K`: returns `action` to the kernel.
+fn ret(action: u32) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: action,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the filter on a syscall the way the kernel would, returning its action.
+    fn run(filter: &SeccompFilter, arch: u32, syscall: Syscall, args: [u64; 6]) -> u32 {
+        let nr = (0..1024)
+            .find(|&nr| Syscall::from_nr(nr) == Some(syscall))
+            .unwrap();
+        run_nr(filter, arch, nr as u32, args)
+    }
+
+    fn run_nr(filter: &SeccompFilter, arch: u32, nr: u32, args: [u64; 6]) -> u32 {
+        let word = |offset: u32| match offset {
+            NR_OFFSET => nr,
+            ARCH_OFFSET => arch,
+            _ => {
+                let arg = args[((offset - ARGS_OFFSET) / 8) as usize];
+                if offset.is_multiple_of(8) {
+                    arg as u32
+                } else {
+                    (arg >> 32) as u32
+                }
+            }
+        };
+
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter.program[pc];
+            pc += 1;
+            match insn.code as u32 {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => acc = word(insn.k),
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += if acc == insn.k { insn.jt } else { insn.jf } as usize;
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K => {
+                    pc += if acc & insn.k != 0 { insn.jt } else { insn.jf } as usize;
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return insn.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    const TRACE: u32 = libc::SECCOMP_RET_TRACE;
+    const ALLOW: u32 = libc::SECCOMP_RET_ALLOW;
+
+    #[test]
+    fn traces_only_known_syscalls() {
+        let filter = SeccompFilter::new();
+        assert_eq!(run(&filter, AUDIT_ARCH, Syscall::Unlinkat, [0; 6]), TRACE);
+        assert_eq!(run(&filter, AUDIT_ARCH, Syscall::Renameat2, [0; 6]), TRACE);
+        assert_eq!(run_nr(&filter, AUDIT_ARCH, 1023, [0; 6]), ALLOW);
+        // Foreign ABIs number their syscalls differently.
+        assert_eq!(run(&filter, 0x4000_0003, Syscall::Unlinkat, [0; 6]), ALLOW);
+    }
+
+    #[test]
+    fn traces_only_opens_that_may_write() {
+        let filter = SeccompFilter::new();
+        let openat = |flags: i32| {
+            run(
+                &filter,
+                AUDIT_ARCH,
+                Syscall::Openat,
+                [libc::AT_FDCWD as u64, 0, flags as u64, 0o644, 0, 0],
+            )
+        };
+        assert_eq!(openat(libc::O_RDONLY), ALLOW);
+        assert_eq!(
+            openat(libc::O_RDONLY | libc::O_CLOEXEC | libc::O_DIRECTORY),
+            ALLOW
+        );
+        assert_eq!(openat(libc::O_WRONLY), TRACE);
+        assert_eq!(openat(libc::O_RDWR), TRACE);
+        assert_eq!(openat(libc::O_RDONLY | libc::O_CREAT), TRACE);
+        // Linux truncates even when opening read-only.
+        assert_eq!(openat(libc::O_RDONLY | libc::O_TRUNC), TRACE);
+        assert_eq!(openat(libc::O_WRONLY | libc::O_TMPFILE), TRACE);
+
+        // Not every architecture has open.
+        if (0..1024).any(|nr| Syscall::from_nr(nr) == Some(Syscall::Open)) {
+            let open = |flags: i32| {
+                run(
+                    &filter,
+                    AUDIT_ARCH,
+                    Syscall::Open,
+                    [0, flags as u64, 0o644, 0, 0, 0],
+                )
+            };
+            assert_eq!(open(libc::O_RDONLY), ALLOW);
+            assert_eq!(open(libc::O_WRONLY | libc::O_APPEND), TRACE);
+            assert_eq!(open(libc::O_RDONLY | libc::O_CREAT), TRACE);
+        }
+    }
+
+    #[test]
+    fn inherited_filters_do_not_count() {
+        assert!(filters(Pid::this()).is_some());
+        assert!(!is_filtered(Pid::this()));
+    }
+
+    #[test]
+    fn traces_only_mappings_that_write_back() {
+        let filter = SeccompFilter::new();
+        let mmap = |prot: i32, flags: i32| {
+            run(
+                &filter,
+                AUDIT_ARCH,
+                Syscall::Mmap,
+                [0, 4096, prot as u64, flags as u64, 3, 0],
+            )
+        };
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        assert_eq!(mmap(rw, libc::MAP_SHARED), TRACE);
+        assert_eq!(mmap(rw, libc::MAP_SHARED_VALIDATE), TRACE);
+        assert_eq!(mmap(libc::PROT_READ, libc::MAP_SHARED), ALLOW);
+        assert_eq!(mmap(rw, libc::MAP_PRIVATE), ALLOW);
+        assert_eq!(mmap(rw, libc::MAP_SHARED | libc::MAP_ANONYMOUS), ALLOW);
+        assert_eq!(mmap(rw, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS), ALLOW);
+
+        let mprotect = |prot: i32| {
+            run(
+                &filter,
+                AUDIT_ARCH,
+                Syscall::Mprotect,
+                [0, 4096, prot as u64, 0, 0, 0],
+            )
+        };
+        assert_eq!(mprotect(rw), TRACE);
+        assert_eq!(mprotect(libc::PROT_READ | libc::PROT_EXEC), ALLOW);
+    }
+}
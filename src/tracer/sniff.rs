@@ -1,129 +1,632 @@
-use crate::tracer::peek;
+use crate::tracer::fd_path;
 use crate::tracer::string_at;
+use crate::tracer::Syscall;
+use crate::tracer::SyscallRegs;
 
 use nix::errno::Errno;
 use nix::libc;
+use nix::sys::ptrace;
 use nix::unistd::Pid;
-use std::ffi::c_ulonglong;
+use std::ffi::{c_ulonglong, c_void, OsString};
 use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Change {
-    Created(String),
-    Deleted(String),
-    Changed(String),
+    Created(PathBuf),
+    Deleted(PathBuf),
+    Changed(PathBuf),
+    /// `from` was moved to `to`, replacing whatever `to` held before.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// `from` and `to` swapped places (`RENAME_EXCHANGE`).
+    Exchanged {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// The file was cut or extended to `length` bytes.
+    Truncated {
+        path: PathBuf,
+        length: u64,
+    },
+    /// The file's permission bits were set to `mode`.
+    ModeChanged {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// The file's owner and/or group were changed; `None` leaves one unchanged.
+    OwnerChanged {
+        path: PathBuf,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// A directory was created with permission bits `mode`.
+    DirCreated {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// An empty directory was removed.
+    DirRemoved(PathBuf),
+    /// `link` was created as a new hard link to the existing file `target`.
+    Linked {
+        target: PathBuf,
+        link: PathBuf,
+    },
+    /// `link` was created as a symlink pointing at `target`.
+    Symlinked {
+        target: PathBuf,
+        link: PathBuf,
+    },
+    /// A special file (FIFO, socket or device node) was created with `mode`.
+    NodeCreated {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// The file's access and/or modification times were set.
+    TimesChanged(PathBuf),
+    /// The extended attribute `name` was set on the file.
+    XattrSet {
+        path: PathBuf,
+        name: OsString,
+    },
+    /// The extended attribute `name` was removed from the file.
+    XattrRemoved {
+        path: PathBuf,
+        name: OsString,
+    },
+    /// The file was mapped into memory shared and writable, so stores to the
+    /// mapping change it without any further syscall.
+    Mapped(PathBuf),
+}
+
+impl Change {
+    /// Returns the paths affected by the change.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Change::Created(path)
+            | Change::Deleted(path)
+            | Change::Changed(path)
+            | Change::DirRemoved(path)
+            | Change::Mapped(path)
+            | Change::TimesChanged(path)
+            | Change::Truncated { path, .. }
+            | Change::ModeChanged { path, .. }
+            | Change::OwnerChanged { path, .. }
+            | Change::DirCreated { path, .. }
+            | Change::NodeCreated { path, .. }
+            | Change::XattrSet { path, .. }
+            | Change::XattrRemoved { path, .. } => vec![path],
+            Change::Linked { link, .. } | Change::Symlinked { link, .. } => vec![link],
+            Change::Renamed { from, to } | Change::Exchanged { from, to } => vec![from, to],
+        }
+    }
+
+    /// Returns the directories whose entries the change adds or removes.
+    ///
+    /// Their modification times change along with the entries, so they need
+    /// backing up too for a revert to leave them as they were.
+    pub fn directories(&self) -> Vec<&Path> {
+        let entries = match self {
+            Change::Created(_)
+            | Change::Deleted(_)
+            | Change::DirCreated { .. }
+            | Change::DirRemoved(_)
+            | Change::Renamed { .. }
+            | Change::Exchanged { .. }
+            | Change::Linked { .. }
+            | Change::Symlinked { .. }
+            | Change::NodeCreated { .. } => self.paths(),
+            _ => Vec::new(),
+        };
+        entries.into_iter().filter_map(Path::parent).collect()
+    }
 }
 
 impl fmt::Display for Change {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Change::Created(path) => write!(f, "File created: {}", path),
-            Change::Deleted(path) => write!(f, "File deleted: {}", path),
-            Change::Changed(path) => write!(f, "File changed: {}", path),
+            Change::Created(path) => write!(f, "File created: {}", path.display()),
+            Change::Deleted(path) => write!(f, "File deleted: {}", path.display()),
+            Change::Changed(path) => write!(f, "File changed: {}", path.display()),
+            Change::Renamed { from, to } => {
+                write!(f, "File renamed: {} -> {}", from.display(), to.display())
+            }
+            Change::Exchanged { from, to } => write!(
+                f,
+                "Files exchanged: {} <-> {}",
+                from.display(),
+                to.display()
+            ),
+            Change::Truncated { path, length } => {
+                write!(f, "File truncated: {} ({} bytes)", path.display(), length)
+            }
+            Change::ModeChanged { path, mode } => {
+                write!(f, "Mode changed: {} ({:04o})", path.display(), mode)
+            }
+            Change::OwnerChanged { path, uid, gid } => {
+                let id = |id: &Option<u32>| id.map_or("unchanged".to_string(), |id| id.to_string());
+                write!(
+                    f,
+                    "Owner changed: {} (uid {}, gid {})",
+                    path.display(),
+                    id(uid),
+                    id(gid)
+                )
+            }
+            Change::DirCreated { path, mode } => {
+                write!(f, "Directory created: {} ({:04o})", path.display(), mode)
+            }
+            Change::DirRemoved(path) => write!(f, "Directory removed: {}", path.display()),
+            Change::TimesChanged(path) => write!(f, "Times changed: {}", path.display()),
+            Change::Mapped(path) => write!(f, "File mapped for writing: {}", path.display()),
+            Change::Linked { target, link } => {
+                write!(
+                    f,
+                    "Hard link created: {} -> {}",
+                    link.display(),
+                    target.display()
+                )
+            }
+            Change::Symlinked { target, link } => {
+                write!(
+                    f,
+                    "Symlink created: {} -> {}",
+                    link.display(),
+                    target.display()
+                )
+            }
+            Change::NodeCreated { path, mode } => {
+                write!(f, "Node created: {} ({:06o})", path.display(), mode)
+            }
+            Change::XattrSet { path, name } => {
+                write!(
+                    f,
+                    "Extended attribute set: {} ({})",
+                    path.display(),
+                    name.to_string_lossy()
+                )
+            }
+            Change::XattrRemoved { path, name } => {
+                write!(
+                    f,
+                    "Extended attribute removed: {} ({})",
+                    path.display(),
+                    name.to_string_lossy()
+                )
+            }
         }
     }
 }
 
-pub fn sniff(pid: Pid) -> Result<Change, nix::Error> {
-    let regs = peek(pid).unwrap();
-    let syscall: c_ulonglong;
-    #[cfg(target_arch = "x86_64")]
-    {
-        syscall = regs.orig_rax;
+/// Works out the change the syscall `pid` is entering with `regs` would make.
+///
+/// Syscalls that change nothing on disk, such as read-only opens, are rejected
+/// with `EINVAL`.
+pub fn sniff(pid: Pid, regs: &SyscallRegs) -> Result<Change, nix::Error> {
+    let syscall = Syscall::from_nr(regs.nr()).ok_or(Errno::EINVAL)?;
+
+    // Path-taking syscalls without a dirfd resolve against the working directory.
+    let cwd = libc::AT_FDCWD as c_ulonglong;
+    let nofollow = libc::AT_SYMLINK_NOFOLLOW;
+
+    let change: Change = match syscall {
+        Syscall::Open => {
+            let flags = regs.arg(1) as i32;
+            open_change(
+                resolve_at(pid, cwd, regs.arg(0), open_at_flags(flags))?,
+                flags,
+            )?
+        }
+        Syscall::Truncate => Change::Truncated {
+            path: resolve_at(pid, cwd, regs.arg(0), 0)?,
+            length: regs.arg(1),
+        },
+        Syscall::Ftruncate => Change::Truncated {
+            path: resolve_fd(pid, regs.arg(0))?,
+            length: regs.arg(1),
+        },
+        Syscall::Rename => rename_change(
+            resolve_at(pid, cwd, regs.arg(0), nofollow)?,
+            resolve_at(pid, cwd, regs.arg(1), nofollow)?,
+            0,
+        ),
+        Syscall::Mkdir => Change::DirCreated {
+            path: resolve_at(pid, cwd, regs.arg(0), nofollow)?,
+            mode: regs.arg(1) as u32,
+        },
+        Syscall::Rmdir => Change::DirRemoved(resolve_at(pid, cwd, regs.arg(0), nofollow)?),
+        Syscall::Creat => open_change(resolve_at(pid, cwd, regs.arg(0), 0)?, CREAT_FLAGS)?,
+        Syscall::Link => Change::Linked {
+            target: resolve_at(pid, cwd, regs.arg(0), nofollow)?,
+            link: resolve_at(pid, cwd, regs.arg(1), nofollow)?,
+        },
+        Syscall::Unlink => Change::Deleted(resolve_at(pid, cwd, regs.arg(0), nofollow)?),
+        Syscall::Symlink => Change::Symlinked {
+            target: PathBuf::from(string_at(pid, regs.arg(0))?),
+            link: resolve_at(pid, cwd, regs.arg(1), nofollow)?,
+        },
+        Syscall::Chmod => Change::ModeChanged {
+            path: resolve_at(pid, cwd, regs.arg(0), 0)?,
+            mode: regs.arg(1) as u32,
+        },
+        Syscall::Fchmod => Change::ModeChanged {
+            path: resolve_fd(pid, regs.arg(0))?,
+            mode: regs.arg(1) as u32,
+        },
+        Syscall::Chown | Syscall::Lchown => {
+            let flags = if syscall == Syscall::Lchown {
+                nofollow
+            } else {
+                0
+            };
+            owner_change(
+                resolve_at(pid, cwd, regs.arg(0), flags)?,
+                regs.arg(1),
+                regs.arg(2),
+            )
+        }
+        Syscall::Fchown => owner_change(resolve_fd(pid, regs.arg(0))?, regs.arg(1), regs.arg(2)),
+        Syscall::Mknod => node_change(
+            resolve_at(pid, cwd, regs.arg(0), nofollow)?,
+            regs.arg(1) as u32,
+        ),
+        Syscall::Setxattr | Syscall::Lsetxattr => {
+            let flags = if syscall == Syscall::Lsetxattr {
+                nofollow
+            } else {
+                0
+            };
+            Change::XattrSet {
+                path: resolve_at(pid, cwd, regs.arg(0), flags)?,
+                name: string_at(pid, regs.arg(1))?,
+            }
+        }
+        Syscall::Fsetxattr => Change::XattrSet {
+            path: resolve_fd(pid, regs.arg(0))?,
+            name: string_at(pid, regs.arg(1))?,
+        },
+        Syscall::Removexattr | Syscall::Lremovexattr => {
+            let flags = if syscall == Syscall::Lremovexattr {
+                nofollow
+            } else {
+                0
+            };
+            Change::XattrRemoved {
+                path: resolve_at(pid, cwd, regs.arg(0), flags)?,
+                name: string_at(pid, regs.arg(1))?,
+            }
+        }
+        Syscall::Fremovexattr => Change::XattrRemoved {
+            path: resolve_fd(pid, regs.arg(0))?,
+            name: string_at(pid, regs.arg(1))?,
+        },
+        Syscall::Openat => {
+            let flags = regs.arg(2) as i32;
+            open_change(
+                resolve_at(pid, regs.arg(0), regs.arg(1), open_at_flags(flags))?,
+                flags,
+            )?
+        }
+        Syscall::Mkdirat => Change::DirCreated {
+            path: resolve_at(pid, regs.arg(0), regs.arg(1), nofollow)?,
+            mode: regs.arg(2) as u32,
+        },
+        Syscall::Mknodat => node_change(
+            resolve_at(pid, regs.arg(0), regs.arg(1), nofollow)?,
+            regs.arg(2) as u32,
+        ),
+        Syscall::Fchownat => owner_change(
+            resolve_at(pid, regs.arg(0), regs.arg(1), regs.arg(4) as i32)?,
+            regs.arg(2),
+            regs.arg(3),
+        ),
+        Syscall::Utime | Syscall::Utimes => {
+            Change::TimesChanged(resolve_at(pid, cwd, regs.arg(0), 0)?)
+        }
+        Syscall::Futimesat => {
+            Change::TimesChanged(resolve_optional_at(pid, regs.arg(0), regs.arg(1), 0)?)
+        }
+        Syscall::Utimensat => Change::TimesChanged(resolve_optional_at(
+            pid,
+            regs.arg(0),
+            regs.arg(1),
+            regs.arg(3) as i32,
+        )?),
+        Syscall::Unlinkat => {
+            let path = resolve_at(pid, regs.arg(0), regs.arg(1), nofollow)?;
+            if regs.arg(2) as i32 & libc::AT_REMOVEDIR != 0 {
+                Change::DirRemoved(path)
+            } else {
+                Change::Deleted(path)
+            }
+        }
+        Syscall::Renameat | Syscall::Renameat2 => {
+            let flags = if syscall == Syscall::Renameat2 {
+                regs.arg(4) as u32
+            } else {
+                0
+            };
+            rename_change(
+                resolve_at(pid, regs.arg(0), regs.arg(1), nofollow)?,
+                resolve_at(pid, regs.arg(2), regs.arg(3), nofollow)?,
+                flags,
+            )
+        }
+        Syscall::Linkat => {
+            let flags = regs.arg(4) as i32;
+            let target_flags = if flags & libc::AT_SYMLINK_FOLLOW != 0 {
+                flags & libc::AT_EMPTY_PATH
+            } else {
+                (flags & libc::AT_EMPTY_PATH) | nofollow
+            };
+            Change::Linked {
+                target: resolve_at(pid, regs.arg(0), regs.arg(1), target_flags)?,
+                link: resolve_at(pid, regs.arg(2), regs.arg(3), nofollow)?,
+            }
+        }
+        Syscall::Symlinkat => Change::Symlinked {
+            target: PathBuf::from(string_at(pid, regs.arg(0))?),
+            link: resolve_at(pid, regs.arg(1), regs.arg(2), nofollow)?,
+        },
+        Syscall::Fchmodat | Syscall::Fchmodat2 => {
+            let flags = if syscall == Syscall::Fchmodat2 {
+                regs.arg(3) as i32
+            } else {
+                0
+            };
+            Change::ModeChanged {
+                path: resolve_at(pid, regs.arg(0), regs.arg(1), flags)?,
+                mode: regs.arg(2) as u32,
+            }
+        }
+        Syscall::Openat2 => {
+            // The flags are the first field of struct open_how
+            let flags = ptrace::read(pid, regs.arg(2) as *mut c_void)? as i32;
+            open_change(
+                resolve_at(pid, regs.arg(0), regs.arg(1), open_at_flags(flags))?,
+                flags,
+            )?
+        }
+        Syscall::Fallocate => Change::Changed(resolve_fd(pid, regs.arg(0))?),
+        // Only the destination, the third argument, is written to.
+        Syscall::CopyFileRange => Change::Changed(resolve_fd(pid, regs.arg(2))?),
+        Syscall::Mmap => {
+            if !writes_back(regs.arg(2) as i32, regs.arg(3) as i32) {
+                return Err(Errno::EINVAL);
+            }
+            map_change(resolve_fd(pid, regs.arg(4))?)?
+        }
+        Syscall::Mprotect => {
+            if regs.arg(2) as i32 & libc::PROT_WRITE == 0 {
+                return Err(Errno::EINVAL);
+            }
+            map_change(resolve_mapping(pid, regs.arg(0))?)?
+        }
+    };
+
+    Ok(change)
+}
+
+/// The flags `creat(path, mode)` is equivalent to passing to `open`.
+const CREAT_FLAGS: i32 = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC;
+
+/// Classifies an open of `path` by its flags.
+///
+/// Read-only opens and anonymous `O_TMPFILE` files change nothing on disk and
+/// are rejected with `EINVAL`. `O_CREAT` on a path that does not exist yet is a
+/// creation; anything else that can write to the file is a change.
+fn open_change(path: PathBuf, flags: i32) -> Result<Change, nix::Error> {
+    if flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        return Err(Errno::EINVAL);
+    }
+
+    // O_TRUNC truncates even when the file is opened read-only.
+    let writable =
+        flags & libc::O_ACCMODE != libc::O_RDONLY || flags & (libc::O_CREAT | libc::O_TRUNC) != 0;
+    if !writable {
+        return Err(Errno::EINVAL);
+    }
+
+    let exists = fs::symlink_metadata(&path).is_ok();
+    if flags & libc::O_CREAT != 0 && (flags & libc::O_EXCL != 0 || !exists) {
+        Ok(Change::Created(path))
+    } else {
+        Ok(Change::Changed(path))
+    }
+}
+
+/// Tells whether an `mmap` with `prot` and `flags` can write to the mapped file.
+///
+/// Only shared, writable mappings of a file carry stores back to disk.
+fn writes_back(prot: i32, flags: i32) -> bool {
+    let shared = matches!(
+        flags & libc::MAP_TYPE,
+        libc::MAP_SHARED | libc::MAP_SHARED_VALIDATE
+    );
+    shared && prot & libc::PROT_WRITE != 0 && flags & libc::MAP_ANONYMOUS == 0
+}
+
+/// Builds a mapping change, which only matters for regular files.
+///
+/// Shared mappings of devices, memfds and the like are rejected with `EINVAL`.
+fn map_change(path: PathBuf) -> Result<Change, nix::Error> {
+    if !path.is_file() {
+        return Err(Errno::EINVAL);
+    }
+    Ok(Change::Mapped(path))
+}
+
+/// Builds an ownership change; an id of `-1` leaves that id unchanged.
+fn owner_change(path: PathBuf, uid: c_ulonglong, gid: c_ulonglong) -> Change {
+    let id = |id: c_ulonglong| (id as u32 != u32::MAX).then_some(id as u32);
+    Change::OwnerChanged {
+        path,
+        uid: id(uid),
+        gid: id(gid),
+    }
+}
+
+/// Classifies an `mknod` by its file type.
+///
+/// `mknod` can also create regular files, which are reported like `creat`.
+fn node_change(path: PathBuf, mode: u32) -> Change {
+    if mode & libc::S_IFMT == libc::S_IFREG || mode & libc::S_IFMT == 0 {
+        Change::Created(path)
+    } else {
+        Change::NodeCreated { path, mode }
+    }
+}
+
+/// Classifies a rename of `from` to `to` by its `renameat2` flags.
+///
+/// `RENAME_EXCHANGE` swaps the two paths instead of moving one over the other.
+/// `RENAME_NOREPLACE` needs no special handling: the call fails rather than
+/// replace an existing `to`, and failed syscalls are discarded on exit.
+fn rename_change(from: PathBuf, to: PathBuf, flags: u32) -> Change {
+    if flags & libc::RENAME_EXCHANGE != 0 {
+        Change::Exchanged { from, to }
+    } else {
+        Change::Renamed { from, to }
     }
-    #[cfg(target_arch = "aarch64")]
+}
+
+/// Translates `open` flags into the `AT_*` flags describing how the path resolves.
+fn open_at_flags(flags: i32) -> i32 {
+    // O_CREAT | O_EXCL fails on a symlink rather than creating its target.
+    if flags & libc::O_NOFOLLOW != 0
+        || flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL
     {
-        syscall = regs.regs[8];
+        libc::AT_SYMLINK_NOFOLLOW
+    } else {
+        0
     }
+}
 
-    let change: Change = match syscall {
-        2 => {
-            // open
-            #[cfg(target_arch = "x86_64")]
-            {
-                Change::Changed(string_at(pid, regs.rdi)?)
-            }
-            #[cfg(target_arch = "aarch64")]
-            {
-                Change::Changed(string_at(pid, regs.regs[0])?)
-            }
-        }
-        76 | 77 => return Ok(Change::Changed(syscall.to_string())), // truncate, ftruncate
-        80 | 81 => return Ok(Change::Changed(syscall.to_string())), // chdir, fchdir
-        83 | 84 => return Ok(Change::Changed(syscall.to_string())), // mkdir, rmdir
-        85 => {
-            // creat
-            #[cfg(target_arch = "x86_64")]
-            {
-                Change::Created(string_at(pid, regs.rdi)?)
-            }
-            #[cfg(target_arch = "aarch64")]
-            {
-                Change::Created(string_at(pid, regs.regs[0])?)
-            }
-        }
-        86 => return Ok(Change::Changed(syscall.to_string())), // link
-        87 => {
-            // unlink
-            #[cfg(target_arch = "x86_64")]
-            {
-                Change::Deleted(string_at(pid, regs.rdi)?)
-            }
-            #[cfg(target_arch = "aarch64")]
-            {
-                Change::Deleted(string_at(pid, regs.regs[0])?)
-            }
-        }
-        88 => return Ok(Change::Changed(syscall.to_string())), // symlink
-        90..95 => return Ok(Change::Changed(syscall.to_string())), // chmod, fchmod, chown, fchown, lchown, umask
-        133 => return Ok(Change::Changed(syscall.to_string())), // mknod
-        161 => return Ok(Change::Changed(syscall.to_string())), // chroot
-        188..199 => return Ok(Change::Changed(syscall.to_string())), // xattr stuff
-        257 => {
-            // openat
-            #[cfg(target_arch = "x86_64")]
-            {
-                let dirfd = regs.rdi;
-                let pathname = string_at(pid, regs.rsi)?;
-
-                let full_path = if dirfd == libc::AT_FDCWD as u64 {
-                    // If dirfd is AT_FDCWD, treat pathname as relative to the current working directory
-                    let cwd = resolve_cwd(pid)?;
-                    cwd.join(pathname)
-                } else {
-                    // If dirfd is not AT_FDCWD, use it to resolve the path
-                    let dir_path = resolve_dirfd_to_path(pid, dirfd)?;
-                    dir_path.join(pathname)
-                };
-
-                Change::Changed(full_path.to_str().unwrap_or("").to_string())
-            }
-            #[cfg(target_arch = "aarch64")]
-            {
-                let dirfd = regs.regs[0];
-                let pathname = string_at(pid, regs.regs[1])?;
-
-                let full_path = if dirfd == libc::AT_FDCWD as u64 {
-                    // If dirfd is AT_FDCWD, treat pathname as relative to the current working directory
-                    let cwd = resolve_cwd(pid)?;
-                    cwd.join(pathname)
-                } else {
-                    // If dirfd is not AT_FDCWD, use it to resolve the path
-                    let dir_path = resolve_dirfd_to_path(pid, dirfd)?;
-                    dir_path.join(pathname)
-                };
-
-                Change::Changed(full_path.to_str().unwrap_or("").to_string())
-            }
-        }
-        258..261 | 263..269 => return Ok(Change::Changed(syscall.to_string())), // mkdirat and friends
-        437 => return Ok(Change::Changed(syscall.to_string())), // openat2
-        _ => return Err(nix::Error::from(nix::errno::Errno::EINVAL)),
+/// Resolves `pathname` in the tracee's memory against the directory `dirfd`.
+///
+/// The result is a canonical absolute path. The final component is a symlink's
+/// target unless `AT_SYMLINK_NOFOLLOW` is set, in which case it names the link
+/// itself. With `AT_EMPTY_PATH` an empty `pathname` refers to `dirfd` itself.
+fn resolve_at(
+    pid: Pid,
+    dirfd: c_ulonglong,
+    pathname: c_ulonglong,
+    flags: i32,
+) -> Result<PathBuf, nix::Error> {
+    let pathname = PathBuf::from(string_at(pid, pathname)?);
+    let dirfd = dirfd as i32;
+
+    let full_path = if pathname.as_os_str().is_empty() && flags & libc::AT_EMPTY_PATH != 0 {
+        if dirfd == libc::AT_FDCWD {
+            resolve_cwd(pid)?
+        } else {
+            resolve_dirfd_to_path(pid, dirfd)?
+        }
+    } else if pathname.is_absolute() {
+        // Absolute paths ignore dirfd entirely
+        pathname
+    } else if dirfd == libc::AT_FDCWD {
+        // If dirfd is AT_FDCWD, treat pathname as relative to the current working directory
+        let cwd = resolve_cwd(pid)?;
+        cwd.join(pathname)
+    } else {
+        // If dirfd is not AT_FDCWD, use it to resolve the path
+        let dir_path = resolve_dirfd_to_path(pid, dirfd)?;
+        dir_path.join(pathname)
     };
 
-    Ok(change)
+    Ok(canonicalize(
+        full_path,
+        flags & libc::AT_SYMLINK_NOFOLLOW == 0,
+    ))
+}
+
+/// Like `resolve_at`, but a null `pathname` refers to `dirfd` itself.
+///
+/// This is how `futimens` and `futimes` are implemented on top of `utimensat`
+/// and `futimesat`.
+fn resolve_optional_at(
+    pid: Pid,
+    dirfd: c_ulonglong,
+    pathname: c_ulonglong,
+    flags: i32,
+) -> Result<PathBuf, nix::Error> {
+    if pathname == 0 {
+        resolve_fd(pid, dirfd)
+    } else {
+        resolve_at(pid, dirfd, pathname, flags)
+    }
+}
+
+/// Resolves `.`, `..` and symlinks in `path` as far as they exist on disk.
+///
+/// The final component is only followed if `follow` is set. Paths that do not
+/// exist yet keep their final component, with the parent canonicalized instead.
+fn canonicalize(path: PathBuf, follow: bool) -> PathBuf {
+    if follow {
+        if let Ok(canonical) = fs::canonicalize(&path) {
+            return canonical;
+        }
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path,
+        },
+        _ => fs::canonicalize(&path).unwrap_or(path),
+    }
+}
+
+/// Resolves a file descriptor of a traced process to the path it refers to.
+///
+/// Fds that are not backed by a path, such as pipes, are rejected with `EBADF`.
+fn resolve_fd(pid: Pid, fd: c_ulonglong) -> Result<PathBuf, nix::Error> {
+    fd_path(pid, fd as i32).ok_or(Errno::EBADF)
+}
+
+/// Finds the file behind the shared mapping containing `addr` in a traced process.
+///
+/// Private and anonymous mappings, which never write back to a file, are
+/// rejected with `EINVAL`.
+fn resolve_mapping(pid: Pid, addr: c_ulonglong) -> Result<PathBuf, nix::Error> {
+    let maps = fs::read(format!("/proc/{}/maps", pid))
+        .map_err(|err| Errno::from_raw(err.raw_os_error().unwrap_or(libc::EINVAL)))?;
+
+    // Each line reads "start-end perms offset dev inode    pathname".
+    for line in maps.split(|&byte| byte == b'\n') {
+        let mut fields = line.splitn(6, |&byte| byte == b' ');
+        let (Some(range), Some(perms)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Some((start, end)) = std::str::from_utf8(range)
+            .ok()
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(start, end)| {
+                Some((
+                    u64::from_str_radix(start, 16).ok()?,
+                    u64::from_str_radix(end, 16).ok()?,
+                ))
+            })
+        else {
+            continue;
+        };
+        if !(start..end).contains(&addr) {
+            continue;
+        }
+
+        let pathname = fields.nth(3).unwrap_or_default().trim_ascii_start();
+        if perms.get(3) != Some(&b's') || !pathname.starts_with(b"/") {
+            return Err(Errno::EINVAL);
+        }
+        return Ok(PathBuf::from(OsString::from_vec(pathname.to_vec())));
+    }
+
+    Err(Errno::EINVAL)
 }
 
 /// Resolves the current working directory of a traced process.
@@ -136,10 +639,168 @@ fn resolve_cwd(pid: Pid) -> Result<PathBuf, nix::Error> {
 }
 
 /// Resolves a directory file descriptor to an actual directory path.
-fn resolve_dirfd_to_path(pid: Pid, dirfd: c_ulonglong) -> Result<PathBuf, nix::Error> {
+fn resolve_dirfd_to_path(pid: Pid, dirfd: i32) -> Result<PathBuf, nix::Error> {
     let dirfd_path = format!("/proc/{}/fd/{}", pid, dirfd);
     match fs::read_link(&dirfd_path) {
         Ok(path) => Ok(path),
-        Err(err) => Err(nix::Error::from_raw(err.raw_os_error().unwrap_or(libc::EINVAL))),
+        Err(err) => Err(nix::Error::from_raw(
+            err.raw_os_error().unwrap_or(libc::EINVAL),
+        )),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path that cannot exist, so `O_CREAT` on it is always a creation.
+    const MISSING: &str = "/nonexistent/undo-sniff-test";
+
+    #[test]
+    fn read_only_opens_change_nothing() {
+        let change = open_change(PathBuf::from("/"), libc::O_RDONLY);
+        assert_eq!(change, Err(Errno::EINVAL));
+        let change = open_change(PathBuf::from("/"), libc::O_RDONLY | libc::O_CLOEXEC);
+        assert_eq!(change, Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn anonymous_tmpfiles_change_nothing() {
+        let change = open_change(PathBuf::from("/tmp"), libc::O_TMPFILE | libc::O_RDWR);
+        assert_eq!(change, Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn writable_opens_are_changes() {
+        let path = PathBuf::from("/");
+        for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+            assert_eq!(
+                open_change(path.clone(), flags),
+                Ok(Change::Changed(path.clone()))
+            );
+        }
+        // O_CREAT on an existing path just opens it.
+        assert_eq!(
+            open_change(path.clone(), libc::O_CREAT | libc::O_WRONLY),
+            Ok(Change::Changed(path))
+        );
+    }
+
+    #[test]
+    fn creating_opens_are_creations() {
+        let path = PathBuf::from(MISSING);
+        assert_eq!(
+            open_change(path.clone(), CREAT_FLAGS),
+            Ok(Change::Created(path.clone()))
+        );
+        // O_CREAT alone, even read-only, may create the file.
+        assert_eq!(
+            open_change(path.clone(), libc::O_CREAT),
+            Ok(Change::Created(path))
+        );
+        // With O_EXCL the call fails unless it creates the file.
+        let path = PathBuf::from("/");
+        assert_eq!(
+            open_change(path.clone(), libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY),
+            Ok(Change::Created(path))
+        );
+    }
+
+    #[test]
+    fn open_at_flags_follow_symlinks_by_default() {
+        assert_eq!(open_at_flags(libc::O_RDWR), 0);
+        assert_eq!(open_at_flags(libc::O_CREAT | libc::O_WRONLY), 0);
+        assert_eq!(
+            open_at_flags(libc::O_RDWR | libc::O_NOFOLLOW),
+            libc::AT_SYMLINK_NOFOLLOW
+        );
+        assert_eq!(
+            open_at_flags(libc::O_CREAT | libc::O_EXCL),
+            libc::AT_SYMLINK_NOFOLLOW
+        );
+        // O_EXCL without O_CREAT is ignored.
+        assert_eq!(open_at_flags(libc::O_EXCL), 0);
+    }
+
+    #[test]
+    fn rename_flags() {
+        let (from, to) = (PathBuf::from("/a"), PathBuf::from("/b"));
+        assert_eq!(
+            rename_change(from.clone(), to.clone(), 0),
+            Change::Renamed {
+                from: from.clone(),
+                to: to.clone()
+            }
+        );
+        assert_eq!(
+            rename_change(from.clone(), to.clone(), libc::RENAME_NOREPLACE),
+            Change::Renamed {
+                from: from.clone(),
+                to: to.clone()
+            }
+        );
+        assert_eq!(
+            rename_change(from.clone(), to.clone(), libc::RENAME_EXCHANGE),
+            Change::Exchanged { from, to }
+        );
+    }
+
+    #[test]
+    fn owner_ids_of_minus_one_are_unchanged() {
+        let path = PathBuf::from("/a");
+        assert_eq!(
+            owner_change(path.clone(), 1000, u32::MAX as u64),
+            Change::OwnerChanged {
+                path: path.clone(),
+                uid: Some(1000),
+                gid: None
+            }
+        );
+        // A 32-bit -1 sign-extended into the register still means unchanged.
+        assert_eq!(
+            owner_change(path.clone(), u64::MAX, 0),
+            Change::OwnerChanged {
+                path,
+                uid: None,
+                gid: Some(0)
+            }
+        );
+    }
+
+    #[test]
+    fn node_types() {
+        let path = PathBuf::from("/a");
+        assert_eq!(
+            node_change(path.clone(), libc::S_IFREG | 0o644),
+            Change::Created(path.clone())
+        );
+        // A type of zero means a regular file.
+        assert_eq!(
+            node_change(path.clone(), 0o644),
+            Change::Created(path.clone())
+        );
+        assert_eq!(
+            node_change(path.clone(), libc::S_IFIFO | 0o600),
+            Change::NodeCreated {
+                path,
+                mode: libc::S_IFIFO | 0o600
+            }
+        );
+    }
+
+    #[test]
+    fn only_shared_writable_file_mappings_write_back() {
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        assert!(writes_back(rw, libc::MAP_SHARED));
+        assert!(writes_back(rw, libc::MAP_SHARED_VALIDATE));
+        assert!(!writes_back(libc::PROT_READ, libc::MAP_SHARED));
+        assert!(!writes_back(rw, libc::MAP_PRIVATE));
+        assert!(!writes_back(rw, libc::MAP_SHARED | libc::MAP_ANONYMOUS));
+    }
+
+    #[test]
+    fn sniff_rejects_unknown_syscalls() {
+        let regs = SyscallRegs::new(u64::MAX, [0; 6], 0);
+        assert_eq!(sniff(Pid::this(), &regs), Err(Errno::EINVAL));
+    }
+}
@@ -1,28 +1,196 @@
+use nix::errno::Errno;
+use nix::libc;
 use nix::sys::ptrace;
 use nix::unistd::Pid;
 use nix::Error;
-use std::ffi::c_void;
+use std::ffi::{c_void, OsString};
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Reads never cross a multiple of this, since the next page may be unmapped.
+/// Every page size Linux supports is a multiple of 4 KiB.
+const CHUNK: usize = 4096;
+
+/// The size of a word read with `PTRACE_PEEKDATA`.
+const WORD: usize = mem::size_of::<libc::c_long>();
+
+/// Set once `process_vm_readv` has failed in a way that will not go away.
+static NO_VM_READV: AtomicBool = AtomicBool::new(false);
 
 /// Reads a null-terminated string from the specified memory address in the target process.
-pub fn string_at(pid: Pid, addr: u64) -> Result<String, Error> {
+///
+/// The bytes are returned as-is, so paths that are not valid UTF-8 survive. Strings
+/// longer than `PATH_MAX` are rejected with `ENAMETOOLONG`.
+pub fn string_at(pid: Pid, addr: u64) -> Result<OsString, Error> {
     let mut bytes = Vec::new();
-    let mut current_addr = addr;
+    let mut addr = addr as usize;
+
+    while bytes.len() < libc::PATH_MAX as usize {
+        let len = (CHUNK - addr % CHUNK).min(libc::PATH_MAX as usize - bytes.len());
+        let chunk = read_chunk(pid, addr, len)?;
+
+        if let Some(nul) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..nul]);
+            return Ok(OsString::from_vec(bytes));
+        }
+
+        bytes.extend_from_slice(&chunk);
+        addr += chunk.len();
+    }
+
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// Reads `len` bytes at `addr`, which must not span a `CHUNK` boundary.
+///
+/// Uses a single `process_vm_readv` call, falling back to `PTRACE_PEEKDATA`
+/// where the former is unavailable or not permitted.
+fn read_chunk(pid: Pid, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    if !NO_VM_READV.load(Ordering::Relaxed) {
+        match vm_read(pid, addr, len) {
+            Ok(bytes) => return Ok(bytes),
+            Err(Errno::ENOSYS) | Err(Errno::EPERM) => NO_VM_READV.store(true, Ordering::Relaxed),
+            Err(e) => return Err(e),
+        }
+    }
+
+    peek_read(pid, addr, len)
+}
+
+/// Reads tracee memory with `process_vm_readv`.
+fn vm_read(pid: Pid, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0u8; len];
+    let local = libc::iovec {
+        iov_base: bytes.as_mut_ptr() as *mut c_void,
+        iov_len: len,
+    };
+    let remote = libc::iovec {
+        iov_base: addr as *mut c_void,
+        iov_len: len,
+    };
+
+    // SAFETY: `local` points to `len` writable bytes owned by `bytes`.
+    let read = unsafe { libc::process_vm_readv(pid.as_raw(), &local, 1, &remote, 1, 0) };
+    let read = Errno::result(read)? as usize;
+    if read == 0 {
+        return Err(Errno::EFAULT);
+    }
+
+    bytes.truncate(read);
+    Ok(bytes)
+}
 
-    loop {
-        let ptr = current_addr as *mut c_void;
-        let word = ptrace::read(pid, ptr).map_err(|e| e)?;
+/// Reads tracee memory one word at a time with `PTRACE_PEEKDATA`.
+fn peek_read(pid: Pid, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    // Aligned words never straddle a page boundary.
+    let start = addr - addr % WORD;
+    let end = (addr + len).next_multiple_of(WORD);
 
-        for i in 0..8 {
-            let byte = (word >> (i * 8) & 0xFF) as u8;
+    let mut bytes = Vec::with_capacity(end - start);
+    for word_addr in (start..end).step_by(WORD) {
+        let word = ptrace::read(pid, word_addr as *mut c_void)?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    Ok(bytes[addr - start..addr - start + len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    fn read_own(bytes: &[u8]) -> Result<OsString, Error> {
+        string_at(Pid::this(), bytes.as_ptr() as u64)
+    }
+
+    /// Two pages, the second of them inaccessible.
+    struct Guarded(*mut u8);
+
+    impl Guarded {
+        fn new() -> Self {
+            // SAFETY: a fresh anonymous mapping, whose second page is then
+            // made inaccessible.
+            unsafe {
+                let map = libc::mmap(
+                    ptr::null_mut(),
+                    2 * CHUNK,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                assert_ne!(map, libc::MAP_FAILED);
+                let map = map as *mut u8;
+                assert_eq!(
+                    libc::mprotect(map.add(CHUNK) as *mut c_void, CHUNK, libc::PROT_NONE),
+                    0
+                );
+                Guarded(map)
+            }
+        }
 
-            if byte == 0 {
-                return String::from_utf8(bytes)
-                    .map_err(|_| Error::from(nix::errno::Errno::EINVAL));
+        /// Places `bytes` so that they end at the inaccessible page.
+        fn place(&self, bytes: &[u8]) -> u64 {
+            // SAFETY: the bytes fit in the first page, which is writable.
+            unsafe {
+                let start = self.0.add(CHUNK - bytes.len());
+                ptr::copy_nonoverlapping(bytes.as_ptr(), start, bytes.len());
+                start as u64
             }
+        }
+    }
 
-            bytes.push(byte);
+    impl Drop for Guarded {
+        fn drop(&mut self) {
+            // SAFETY: the mapping is no longer used.
+            unsafe { libc::munmap(self.0 as *mut c_void, 2 * CHUNK) };
         }
+    }
+
+    #[test]
+    fn stops_at_the_first_nul() {
+        assert_eq!(read_own(b"/tmp/file\0rest\0").unwrap(), "/tmp/file");
+        assert_eq!(read_own(b"\0").unwrap(), "");
+        let invalid = read_own(b"/tmp/\xff\xfe\0").unwrap();
+        assert_eq!(invalid, OsStr::from_bytes(b"/tmp/\xff\xfe"));
+    }
+
+    #[test]
+    fn reads_across_page_boundaries() {
+        let mut bytes = vec![b'a'; 2 * CHUNK];
+        let path: Vec<u8> = (0..100).map(|i| b'a' + i % 26).collect();
+        // Start just before a boundary, so the string is read in two chunks.
+        let offset = (2 * CHUNK - 10 - bytes.as_ptr() as usize % CHUNK) % CHUNK;
+        bytes[offset..offset + path.len()].copy_from_slice(&path);
+        bytes[offset + path.len()] = 0;
+
+        let read = read_own(&bytes[offset..]).unwrap();
+        assert_eq!(read.as_bytes(), path);
+    }
+
+    #[test]
+    fn never_reads_past_the_nul() {
+        let guarded = Guarded::new();
+        let addr = guarded.place(b"/tmp/file\0");
+        assert_eq!(string_at(Pid::this(), addr).unwrap(), "/tmp/file");
+        let addr = guarded.place(b"/tmp/file");
+        assert_eq!(string_at(Pid::this(), addr), Err(Errno::EFAULT));
+    }
 
-        current_addr += 8;
+    #[test]
+    fn rejects_strings_longer_than_path_max() {
+        let max = libc::PATH_MAX as usize;
+        let mut bytes = vec![b'a'; max + 1];
+        bytes[max] = 0;
+        assert_eq!(read_own(&bytes), Err(Errno::ENAMETOOLONG));
+        bytes[max - 1] = 0;
+        assert_eq!(read_own(&bytes).unwrap().len(), max - 1);
+        // With no NUL anywhere, reading stops at the cap.
+        let bytes = vec![b'a'; 4 * max];
+        assert_eq!(read_own(&bytes), Err(Errno::ENAMETOOLONG));
     }
 }
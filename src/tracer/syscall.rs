@@ -0,0 +1,231 @@
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+compile_error!("undo supports tracing on x86_64, aarch64 and riscv64 only");
+
+/// The `AUDIT_ARCH_*` value the kernel reports for native syscalls, the only
+/// ones numbered the way `Syscall::from_nr` expects.
+#[cfg(target_arch = "x86_64")]
+pub const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "riscv64")]
+pub const AUDIT_ARCH: u32 = 0xc000_00f3;
+
+/// A file-mutating syscall, independent of the architecture's numbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    Open,
+    Creat,
+    Openat,
+    Openat2,
+    Truncate,
+    Ftruncate,
+    Rename,
+    Renameat,
+    Renameat2,
+    Mkdir,
+    Mkdirat,
+    Rmdir,
+    Link,
+    Linkat,
+    Unlink,
+    Unlinkat,
+    Symlink,
+    Symlinkat,
+    Mknod,
+    Mknodat,
+    Chmod,
+    Fchmod,
+    Fchmodat,
+    Fchmodat2,
+    Chown,
+    Fchown,
+    Lchown,
+    Fchownat,
+    Futimesat,
+    Utime,
+    Utimes,
+    Utimensat,
+    Setxattr,
+    Lsetxattr,
+    Fsetxattr,
+    Removexattr,
+    Lremovexattr,
+    Fremovexattr,
+    Fallocate,
+    CopyFileRange,
+    Mmap,
+    Mprotect,
+}
+
+impl Syscall {
+    /// Maps a syscall number of the host architecture to a `Syscall`.
+    ///
+    /// Returns `None` for syscalls that do not modify files.
+    pub fn from_nr(nr: u64) -> Option<Syscall> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            x86_64(nr)
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            aarch64(nr)
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            generic(nr)
+        }
+    }
+}
+
+/// The x86_64 syscall table (`arch/x86/entry/syscalls/syscall_64.tbl`).
+#[cfg(any(test, target_arch = "x86_64"))]
+fn x86_64(nr: u64) -> Option<Syscall> {
+    let syscall = match nr {
+        2 => Syscall::Open,
+        9 => Syscall::Mmap,
+        10 => Syscall::Mprotect,
+        76 => Syscall::Truncate,
+        77 => Syscall::Ftruncate,
+        82 => Syscall::Rename,
+        83 => Syscall::Mkdir,
+        84 => Syscall::Rmdir,
+        85 => Syscall::Creat,
+        86 => Syscall::Link,
+        87 => Syscall::Unlink,
+        88 => Syscall::Symlink,
+        90 => Syscall::Chmod,
+        91 => Syscall::Fchmod,
+        92 => Syscall::Chown,
+        93 => Syscall::Fchown,
+        94 => Syscall::Lchown,
+        132 => Syscall::Utime,
+        133 => Syscall::Mknod,
+        188 => Syscall::Setxattr,
+        189 => Syscall::Lsetxattr,
+        190 => Syscall::Fsetxattr,
+        197 => Syscall::Removexattr,
+        198 => Syscall::Lremovexattr,
+        199 => Syscall::Fremovexattr,
+        235 => Syscall::Utimes,
+        257 => Syscall::Openat,
+        258 => Syscall::Mkdirat,
+        259 => Syscall::Mknodat,
+        260 => Syscall::Fchownat,
+        261 => Syscall::Futimesat,
+        263 => Syscall::Unlinkat,
+        264 => Syscall::Renameat,
+        265 => Syscall::Linkat,
+        266 => Syscall::Symlinkat,
+        268 => Syscall::Fchmodat,
+        280 => Syscall::Utimensat,
+        285 => Syscall::Fallocate,
+        316 => Syscall::Renameat2,
+        326 => Syscall::CopyFileRange,
+        437 => Syscall::Openat2,
+        452 => Syscall::Fchmodat2,
+        _ => return None,
+    };
+    Some(syscall)
+}
+
+/// The aarch64 syscall table, which is the generic one plus `renameat`.
+#[cfg(any(test, target_arch = "aarch64"))]
+fn aarch64(nr: u64) -> Option<Syscall> {
+    match nr {
+        38 => Some(Syscall::Renameat),
+        _ => generic(nr),
+    }
+}
+
+/// The generic syscall table (`include/uapi/asm-generic/unistd.h`), as used by riscv64.
+///
+/// Newer architectures only provide the `*at` variants, so there is no `open`,
+/// `creat`, `unlink`, `rename`, `mkdir`, `chmod` or `dup2` here. riscv64 also drops
+/// `renameat` in favour of `renameat2`.
+#[cfg(any(test, target_arch = "aarch64", target_arch = "riscv64"))]
+fn generic(nr: u64) -> Option<Syscall> {
+    let syscall = match nr {
+        5 => Syscall::Setxattr,
+        6 => Syscall::Lsetxattr,
+        7 => Syscall::Fsetxattr,
+        14 => Syscall::Removexattr,
+        15 => Syscall::Lremovexattr,
+        16 => Syscall::Fremovexattr,
+        33 => Syscall::Mknodat,
+        34 => Syscall::Mkdirat,
+        35 => Syscall::Unlinkat,
+        36 => Syscall::Symlinkat,
+        37 => Syscall::Linkat,
+        45 => Syscall::Truncate,
+        46 => Syscall::Ftruncate,
+        47 => Syscall::Fallocate,
+        52 => Syscall::Fchmod,
+        53 => Syscall::Fchmodat,
+        54 => Syscall::Fchownat,
+        55 => Syscall::Fchown,
+        56 => Syscall::Openat,
+        88 => Syscall::Utimensat,
+        222 => Syscall::Mmap,
+        226 => Syscall::Mprotect,
+        276 => Syscall::Renameat2,
+        285 => Syscall::CopyFileRange,
+        437 => Syscall::Openat2,
+        452 => Syscall::Fchmodat2,
+        _ => return None,
+    };
+    Some(syscall)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_64_numbers() {
+        assert_eq!(x86_64(2), Some(Syscall::Open));
+        assert_eq!(x86_64(82), Some(Syscall::Rename));
+        assert_eq!(x86_64(257), Some(Syscall::Openat));
+        assert_eq!(x86_64(316), Some(Syscall::Renameat2));
+        assert_eq!(x86_64(437), Some(Syscall::Openat2));
+        assert_eq!(x86_64(452), Some(Syscall::Fchmodat2));
+        // read, write and stat change nothing on disk.
+        assert_eq!(x86_64(0), None);
+        assert_eq!(x86_64(1), None);
+        assert_eq!(x86_64(4), None);
+    }
+
+    #[test]
+    fn aarch64_numbers() {
+        assert_eq!(aarch64(38), Some(Syscall::Renameat));
+        assert_eq!(aarch64(56), Some(Syscall::Openat));
+        assert_eq!(aarch64(276), Some(Syscall::Renameat2));
+        assert_eq!(aarch64(452), Some(Syscall::Fchmodat2));
+        // read, write and openat's x86_64 number.
+        assert_eq!(aarch64(63), None);
+        assert_eq!(aarch64(64), None);
+        assert_eq!(aarch64(257), None);
+    }
+
+    #[test]
+    fn riscv64_numbers() {
+        assert_eq!(generic(35), Some(Syscall::Unlinkat));
+        assert_eq!(generic(56), Some(Syscall::Openat));
+        assert_eq!(generic(276), Some(Syscall::Renameat2));
+        // riscv64 has no renameat.
+        assert_eq!(generic(38), None);
+        assert_eq!(generic(63), None);
+    }
+
+    #[test]
+    fn host_numbers() {
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(Syscall::from_nr(87), Some(Syscall::Unlink));
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        assert_eq!(Syscall::from_nr(35), Some(Syscall::Unlinkat));
+        assert_eq!(Syscall::from_nr(u64::MAX), None);
+    }
+}